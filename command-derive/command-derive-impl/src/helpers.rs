@@ -1,6 +1,8 @@
 use proc_macro2::Span;
+use std::fmt;
 use syn::{
-    parse::Parse, parse_str, spanned::Spanned, Ident, Lit, Meta, MetaList, NestedMeta, Path,
+    parse::Parse, parse_str, spanned::Spanned, Field, GenericArgument, Ident, Lit, Meta, MetaList,
+    NestedMeta, Path, PathArguments, Type,
 };
 
 /// Parses this: `#key = "<val>"`
@@ -138,3 +140,169 @@ impl CommandMarker {
         Ok(self)
     }
 }
+
+/// How a field is read from protocol args
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum FieldKind {
+    /// Takes exactly one arg
+    Plain,
+    /// `#[command(flatten)]`, reads itself through `FromStrIter`
+    Flatten,
+    /// `Option<T>`, takes one arg if there are any left
+    Optional,
+    /// `Vec<T>`, takes all the remaining args
+    Variadic,
+}
+
+impl FieldKind {
+    pub fn arity(self) -> Arity {
+        match self {
+            FieldKind::Plain => Arity::EXACT_ONE,
+            FieldKind::Optional => Arity {
+                min: 0,
+                max: Some(1),
+            },
+            FieldKind::Flatten | FieldKind::Variadic => Arity::UNKNOWN,
+        }
+    }
+}
+
+/// Options of a single field, parsed from its type and `#[command(...)]`
+pub(crate) struct FieldOpts {
+    pub kind: FieldKind,
+    /// `#[command(with = "module")]`: module with `from_arg` and `to_arg`
+    /// functions used instead of `FromStr` and `ToString`
+    pub with: Option<Path>,
+    pub span: Span,
+}
+
+impl FieldOpts {
+    pub fn of(field: &Field) -> Result<Self, (String, Span)> {
+        let mut flatten = false;
+        let mut with = None;
+        for meta in field.attrs.iter().filter_map(|attr| attr.parse_meta().ok()) {
+            let nested = match &meta {
+                Meta::List(MetaList { path, nested, .. }) if path.is_ident("command") => nested,
+                _ => continue,
+            };
+            for nested in nested {
+                match nested {
+                    NestedMeta::Meta(Meta::Path(path)) if path.is_ident("flatten") => {
+                        flatten = true
+                    }
+                    _ => {
+                        if let Some(path) = ParseAssign("with").parse_arg::<Path>(nested)? {
+                            if with.is_some() {
+                                return Err((
+                                    "`with` specified multiple times".into(),
+                                    nested.span(),
+                                ));
+                            }
+                            with = Some(path);
+                        }
+                    }
+                }
+            }
+        }
+
+        let kind = match generic_wrapper(&field.ty) {
+            _ if flatten => FieldKind::Flatten,
+            Some(wrapper) if wrapper == "Option" => FieldKind::Optional,
+            Some(wrapper) if wrapper == "Vec" => FieldKind::Variadic,
+            _ => FieldKind::Plain,
+        };
+        if flatten && with.is_some() {
+            return Err((
+                "`flatten` and `with` can't be used together".into(),
+                field.span(),
+            ));
+        }
+        Ok(FieldOpts {
+            kind,
+            with,
+            span: field.span(),
+        })
+    }
+
+    /// Optional fields can only be followed by optional ones, so that it's
+    /// always clear which field an arg belongs to. `Vec<T>` has to be the
+    /// last field.
+    pub fn check_order(fields: &[Self]) -> Result<(), (String, Span)> {
+        let mut seen_optional = false;
+        for (i, &FieldOpts { kind, span, .. }) in fields.iter().enumerate() {
+            match kind {
+                FieldKind::Variadic if i + 1 != fields.len() => {
+                    return Err(("`Vec<T>` field must be the last one".into(), span))
+                }
+                FieldKind::Plain | FieldKind::Flatten if seen_optional => {
+                    return Err((
+                        "Required field can't follow an `Option<T>` one".into(),
+                        span,
+                    ))
+                }
+                FieldKind::Optional => seen_optional = true,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns `Wrapper` for types like `Wrapper<T>` and `path::Wrapper<T>`
+fn generic_wrapper(ty: &Type) -> Option<&Ident> {
+    let segment = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args)
+            if matches!(args.args.first(), Some(GenericArgument::Type(_))) =>
+        {
+            Some(&segment.ident)
+        }
+        _ => None,
+    }
+}
+
+/// Range of argument counts a variant accepts, `max == None` means unbounded
+#[derive(Clone, Copy)]
+pub(crate) struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub const EXACT_ONE: Self = Arity {
+        min: 1,
+        max: Some(1),
+    };
+    pub const UNKNOWN: Self = Arity { min: 0, max: None };
+
+    pub fn sum<I: IntoIterator<Item = Self>>(it: I) -> Self {
+        it.into_iter().fold(
+            Arity {
+                min: 0,
+                max: Some(0),
+            },
+            |acc, x| Arity {
+                min: acc.min + x.min,
+                max: acc.max.and_then(|a| x.max.map(|b| a + b)),
+            },
+        )
+    }
+
+    pub fn overlaps(self, other: Self) -> bool {
+        let below = |a: Self, b: Self| a.max.is_some_and(|max| max < b.min);
+        !below(self, other) && !below(other, self)
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{}..={}", self.min, max),
+            None => write!(f, "{} or more", self.min),
+        }
+    }
+}
@@ -1,10 +1,18 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use quote::{format_ident, quote, quote_spanned};
 use syn::{parse_macro_input, spanned::Spanned, Fields, ItemEnum, ItemStruct, Member, Variant};
 
 mod helpers;
-use helpers::{CommandMarker, HandlerOpt, VariantOpts};
+use helpers::{Arity, CommandMarker, FieldKind, FieldOpts, HandlerOpt, VariantOpts};
 
+/// Derives `Command` for enums, each variant being a message with its
+/// `#[command(code = "...")]`
+///
+/// Variants may share a code. Such overloads are told apart only by the
+/// number of args, never by whether the args parse, so overloads taking
+/// the same number of args are a compile error. A `#[command(flatten)]`
+/// field takes any number of args, so its variant can't be overloaded.
 #[proc_macro_derive(Command, attributes(command))]
 pub fn command_derive(input: TokenStream) -> TokenStream {
     let ItemEnum {
@@ -34,6 +42,7 @@ pub fn command_derive(input: TokenStream) -> TokenStream {
     let mut named_fields_to_str = Vec::with_capacity(vars.len());
     let mut idx_fields = Vec::with_capacity(vars.len());
     let mut read_fields = Vec::with_capacity(vars.len());
+    let mut arities = Vec::with_capacity(vars.len());
     for var in vars {
         let var_span = var.span();
         let Variant {
@@ -79,106 +88,61 @@ pub fn command_derive(input: TokenStream) -> TokenStream {
             }) => (code, handle),
             Err((err, span)) => return str_as_compile_error(&err, span),
         };
-        let (
-            named_fields_piece,
-            named_fields_to_str_piece,
-            read_fields_piece,
-            idx_fields_piece,
-            pattern,
-        ) = match fields {
+        let (fields, pattern): (Vec<_>, _) = match fields {
             Fields::Named(named) => {
-                let named_iter: Vec<_> = named
+                let fields: Vec<_> = named
                     .named
                     .into_iter()
                     .map(|field| {
-                        (
-                            field.ident.expect("Variant is guaranteed to be named"),
-                            field
-                                .attrs
-                                .into_iter()
-                                .filter_map(|attr| attr.parse_meta().ok())
-                                .any(|meta| CommandMarker("flatten").validate(&meta).is_ok()),
-                        )
+                        let ident = field
+                            .ident
+                            .clone()
+                            .expect("Variant is guaranteed to be named");
+                        (ident.clone(), Member::Named(ident), field)
                     })
                     .collect();
-                let named_fields_piece: Vec<_> =
-                    named_iter.iter().map(|(ident, _)| ident.clone()).collect();
-                let idx_fields_piece: Vec<_> = named_iter
-                    .iter()
-                    .cloned()
-                    .map(|(ident, _)| Member::Named(ident))
-                    .collect();
-                let (named_fields_to_str_piece, read_fields_piece): (Vec<_>, Vec<_>) = named_iter.iter()
-                    .map(|(ident, flatten)| match flatten {
-                        false => (
-                            quote!{ res.push(#ident.to_string()) },
-                            quote!{ args.next().ok_or_else(|| ::anyhow::anyhow!("Not enough args"))?.as_ref().parse().map_err(|e| ::anyhow::anyhow!("{}", e))? }
-                        ),
-                        true => (
-                            quote!{ res.extend(#ident.into_iter()) },
-                            quote!{ ::command_derive::FromStrIter::from_str_iter(&mut args)? }
-                        )
-                    })
-                    .unzip();
+                let bindings = fields.iter().map(|(binding, ..)| binding);
                 let pattern = quote! {
-                    #ident {#(#named_fields_piece,)*}
+                    #ident {#(#bindings,)*}
                 };
-                (
-                    named_fields_piece,
-                    named_fields_to_str_piece,
-                    read_fields_piece,
-                    idx_fields_piece,
-                    pattern,
-                )
+                (fields, pattern)
             }
             Fields::Unnamed(unnamed) => {
-                let field_num = unnamed.unnamed.len();
-                let flatten_flags = unnamed.unnamed.into_iter().map(|field| {
-                    field
-                        .attrs
-                        .into_iter()
-                        .filter_map(|attr| attr.parse_meta().ok())
-                        .any(|meta| CommandMarker("flatten").validate(&meta).is_ok())
-                });
-                let named_fields_piece: Vec<_> =
-                    (0..field_num).map(|i| format_ident!("x{}", i)).collect();
-                let (named_fields_to_str_piece, read_fields_piece): (Vec<_>, Vec<_>) = (0..field_num)
-                    .zip(flatten_flags)
-                    .map(|(i, flatten)| {
-                        let fld_ident = format_ident!("x{}", i);
-                        match flatten {
-                            false => (
-                                quote!{ res.push(#fld_ident.to_string()) },
-                                quote!{ args.next().ok_or_else(|| ::anyhow::anyhow!("Not enough args"))?.as_ref().parse().map_err(|e| ::anyhow::anyhow!("{}", e))? }
-                            ),
-                            true => (
-                                quote!{ res.extend(#fld_ident.into_iter()) },
-                                quote!{ ::command_derive::FromStrIter::from_str_iter(&mut args)? }
-                            )
-                        }
-                    })
-                    .unzip();
-                let idx_fields_piece: Vec<_> =
-                    (0..field_num).map(|i| Member::Unnamed(i.into())).collect();
+                let fields: Vec<_> = unnamed
+                    .unnamed
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, field)| (format_ident!("x{}", i), Member::Unnamed(i.into()), field))
+                    .collect();
+                let bindings = fields.iter().map(|(binding, ..)| binding);
                 let pattern = quote! {
-                    #ident (#(#named_fields_piece,)*)
+                    #ident (#(#bindings,)*)
                 };
-                (
-                    named_fields_piece,
-                    named_fields_to_str_piece,
-                    read_fields_piece,
-                    idx_fields_piece,
-                    pattern,
-                )
+                (fields, pattern)
             }
-            Fields::Unit => (
-                Vec::new(),
-                Vec::new(),
-                Vec::new(),
-                Vec::new(),
-                quote! { #ident },
-            ),
+            Fields::Unit => (Vec::new(), quote! { #ident }),
+        };
+        let opts_res: Result<Vec<_>, _> = fields
+            .iter()
+            .map(|(_, _, field)| FieldOpts::of(field))
+            .collect();
+        let opts = match opts_res.and_then(|opts| FieldOpts::check_order(&opts).map(|_| opts)) {
+            Ok(opts) => opts,
+            Err((err, span)) => return str_as_compile_error(&err, span),
         };
+        let named_fields_piece: Vec<_> =
+            fields.iter().map(|(binding, ..)| binding.clone()).collect();
+        let named_fields_to_str_piece: Vec<_> = opts
+            .iter()
+            .zip(&named_fields_piece)
+            .map(|(opts, binding)| write_field(opts, quote! { #binding }))
+            .collect();
+        let read_fields_piece: Vec<_> = opts
+            .iter()
+            .map(|opts| read_field(opts, quote! { args }))
+            .collect();
+        let idx_fields_piece: Vec<_> = fields.into_iter().map(|(_, member, _)| member).collect();
+        let arity = Arity::sum(opts.iter().map(|opts| opts.kind.arity()));
         var_idents.push(ident);
         codes.push(code);
         if let Some(handle) = handle {
@@ -189,6 +153,80 @@ pub fn command_derive(input: TokenStream) -> TokenStream {
         read_fields.push(read_fields_piece);
         idx_fields.push(idx_fields_piece);
         patterns.push(pattern);
+        arities.push(arity);
+    }
+
+    // Variants sharing a code are overloads, told apart by the number of args
+    let mut overloads: Vec<(&String, Vec<usize>)> = Vec::new();
+    for (i, code) in codes.iter().enumerate() {
+        match overloads.iter_mut().find(|(c, _)| *c == code) {
+            Some((_, group)) => group.push(i),
+            None => overloads.push((code, vec![i])),
+        }
+    }
+    let mut from_protocol_arms = Vec::with_capacity(overloads.len());
+    for (code, group) in &overloads {
+        for (n, &i) in group.iter().enumerate() {
+            if let Some(&j) = group[..n]
+                .iter()
+                .find(|&&j| arities[j].overlaps(arities[i]))
+            {
+                return str_as_compile_error(
+                    &format!(
+                        "Overloads {0}::{1} ({2} args) and {0}::{3} ({4} args) of code `{5}` \
+                         can't be told apart by the number of args",
+                        enum_ident, var_idents[j], arities[j], var_idents[i], arities[i], code,
+                    ),
+                    var_idents[i].span(),
+                );
+            }
+        }
+        let constructors = group.iter().map(|&i| {
+            let var_ident = &var_idents[i];
+            let idx_fields = &idx_fields[i];
+            let read_fields = &read_fields[i];
+            quote! { #enum_ident::#var_ident{#(#idx_fields: #read_fields,)*} }
+        });
+        let arm = match group.as_slice() {
+            [_] => {
+                quote! {
+                    #code => {
+                        let res = #(#constructors)*;
+                        if args.next().is_some() {
+                            return Err(::anyhow::anyhow!("Too many args"));
+                        }
+                        res
+                    }
+                }
+            }
+            _ => {
+                let patterns = group.iter().map(|&i| match arities[i] {
+                    Arity { min: 0, max: None } => quote! { _ },
+                    Arity { min, max: None } => quote! { #min.. },
+                    Arity {
+                        min,
+                        max: Some(max),
+                    } if min == max => quote! { #min },
+                    Arity {
+                        min,
+                        max: Some(max),
+                    } => quote! { #min..=#max },
+                });
+                quote! {
+                    #code => {
+                        let collected: Vec<S> = args.collect();
+                        let mut args = collected.iter();
+                        match collected.len() {
+                            #(#patterns => #constructors,)*
+                            n => return Err(::anyhow::anyhow!(
+                                "No overload of {} takes {} args", #code, n
+                            )),
+                        }
+                    }
+                }
+            }
+        };
+        from_protocol_arms.push(arm);
     }
     let mut res = quote! {
     impl ::command_derive::Command for #enum_ident {
@@ -220,16 +258,9 @@ pub fn command_derive(input: TokenStream) -> TokenStream {
             S: AsRef<str>
         {
             let res = match code {
-                #(
-                    #codes => #enum_ident::#var_idents{#(
-                        #idx_fields: #read_fields,
-                    )*},
-                )*
+                #(#from_protocol_arms)*
                 code => return Err(::anyhow::anyhow!("Unknown command code: {}", code))
             };
-            if args.next().is_some() {
-                return Err(::anyhow::anyhow!("Too many args"));
-            }
             Ok(res)
         }
     }
@@ -253,27 +284,47 @@ pub fn command_derive(input: TokenStream) -> TokenStream {
 /// Derives `IntoIterator<IntoIter=Vec<String>::IntoIter>` for `&Self`
 /// and `FromStrIter` for `Self`
 /// Applyable for struct-s
-#[proc_macro_derive(WithStrIter)]
+#[proc_macro_derive(WithStrIter, attributes(command))]
 pub fn with_str_iter_derive(input: TokenStream) -> TokenStream {
     let ItemStruct {
         ident: struct_ident,
         fields,
         ..
     } = parse_macro_input!(input as ItemStruct);
-    let field_names: Vec<_> = match fields {
+    let fields: Vec<_> = match fields {
         Fields::Named(named) => named
             .named
             .into_iter()
-            .map(|field| Member::Named(field.ident.expect("Fields are guaranteed to be named")))
+            .map(|field| {
+                let ident = field
+                    .ident
+                    .clone()
+                    .expect("Fields are guaranteed to be named");
+                (Member::Named(ident), field)
+            })
             .collect(),
         Fields::Unnamed(unnamed) => unnamed
             .unnamed
             .into_iter()
             .enumerate()
-            .map(|(i, _)| Member::Unnamed(i.into()))
+            .map(|(i, field)| (Member::Unnamed(i.into()), field))
             .collect(),
         Fields::Unit => vec![],
     };
+    let opts_res: Result<Vec<_>, _> = fields
+        .iter()
+        .map(|(_, field)| FieldOpts::of(field))
+        .collect();
+    let opts = match opts_res.and_then(|opts| FieldOpts::check_order(&opts).map(|_| opts)) {
+        Ok(opts) => opts,
+        Err((err, span)) => return str_as_compile_error(&err, span),
+    };
+    let field_names: Vec<_> = fields.into_iter().map(|(name, _)| name).collect();
+    let read_fields = opts.iter().map(|opts| read_field(opts, quote! { it }));
+    let write_fields = opts
+        .iter()
+        .zip(&field_names)
+        .map(|(opts, name)| write_field(opts, quote! { self.#name }));
 
     (quote! {
     impl ::command_derive::FromStrIter for #struct_ident {
@@ -284,14 +335,8 @@ pub fn with_str_iter_derive(input: TokenStream) -> TokenStream {
             S: AsRef<str>,
             I: Iterator<Item=S>
         {
-            let on_err = || ::anyhow::anyhow!("Not enough args");
             Ok(Self {#(
-                #field_names:
-                    it.next()
-                        .ok_or_else(on_err)?
-                        .as_ref()
-                        .parse()
-                        .map_err(|e| ::anyhow::anyhow!("{}", e))?,
+                #field_names: #read_fields,
             )*})
         }
     }
@@ -301,15 +346,67 @@ pub fn with_str_iter_derive(input: TokenStream) -> TokenStream {
         type IntoIter = <Vec<String> as ::core::iter::IntoIterator>::IntoIter;
 
         fn into_iter(self) -> Self::IntoIter {
-            vec![#(
-                self.#field_names.to_string(),
-            )*].into_iter()
+            let mut res = Vec::new();
+            #(#write_fields;)*
+            res.into_iter()
         }
     }
         })
     .into()
 }
 
+/// Expression reading the field from `it`, an iterator over args
+fn read_field(opts: &FieldOpts, it: TokenStream2) -> TokenStream2 {
+    let parse = match &opts.with {
+        Some(with) => {
+            quote! { #with::from_arg(arg.as_ref()).map_err(|e| ::anyhow::anyhow!("{}", e)) }
+        }
+        None => quote! { arg.as_ref().parse().map_err(|e| ::anyhow::anyhow!("{}", e)) },
+    };
+    match opts.kind {
+        FieldKind::Plain => quote! {{
+            let arg = #it.next().ok_or_else(|| ::anyhow::anyhow!("Not enough args"))?;
+            #parse?
+        }},
+        FieldKind::Optional => quote! {
+            #it.next().map(|arg| #parse).transpose()?
+        },
+        FieldKind::Variadic => quote! {
+            #it.by_ref().map(|arg| #parse).collect::<Result<_, ::anyhow::Error>>()?
+        },
+        FieldKind::Flatten => quote! {
+            ::command_derive::FromStrIter::from_str_iter(&mut #it)?
+        },
+    }
+}
+
+/// Statement pushing the field's args into `res`
+fn write_field(opts: &FieldOpts, value: TokenStream2) -> TokenStream2 {
+    let format = |x: TokenStream2| match &opts.with {
+        Some(with) => quote! { #with::to_arg(#x) },
+        None => quote! { #x.to_string() },
+    };
+    match opts.kind {
+        FieldKind::Plain => {
+            let format = format(quote! { (&#value) });
+            quote! { res.push(#format) }
+        }
+        FieldKind::Optional => {
+            let format = format(quote! { x });
+            quote! {
+                if let Some(x) = &#value {
+                    res.push(#format)
+                }
+            }
+        }
+        FieldKind::Variadic => {
+            let format = format(quote! { x });
+            quote! { res.extend(#value.iter().map(|x| #format)) }
+        }
+        FieldKind::Flatten => quote! { res.extend((&#value).into_iter()) },
+    }
+}
+
 fn str_as_compile_error(err: &str, span: proc_macro2::Span) -> TokenStream {
     {
         quote_spanned! {span=> compile_error!(#err); }
@@ -30,3 +30,33 @@ pub trait FromStrIter: Sized {
         S: AsRef<str>,
         I: Iterator<Item = S>;
 }
+
+/// Allows flattening boxed structs, e.g. to keep large variants small
+impl<T: FromStrIter> FromStrIter for Box<T> {
+    type Error = T::Error;
+
+    fn from_str_iter<I, S>(it: I) -> Result<Self, Self::Error>
+    where
+        S: AsRef<str>,
+        I: Iterator<Item = S>,
+    {
+        T::from_str_iter(it).map(Box::new)
+    }
+}
+
+/// Booleans the way AO clients send them: `1` or `0`.
+///
+/// Use with `#[command(with = "command_derive::ao_bool")]`.
+pub mod ao_bool {
+    pub fn from_arg(arg: &str) -> Result<bool, anyhow::Error> {
+        match arg {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(anyhow::anyhow!("Expected 0 or 1, got {:?}", arg)),
+        }
+    }
+
+    pub fn to_arg(value: &bool) -> String {
+        if *value { "1" } else { "0" }.to_string()
+    }
+}
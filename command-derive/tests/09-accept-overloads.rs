@@ -0,0 +1,33 @@
+use command_derive::*;
+
+#[derive(Debug, Command, PartialEq)]
+pub enum ClientCommand {
+    #[command(code = "AM")]
+    MusicList(u32),
+
+    #[command(code = "AM")]
+    AO2MusicList,
+
+    #[command(code = "CC")]
+    SelectCharacter(u32, u32, String),
+
+    #[command(code = "CC")]
+    SelectCharacterNoHdid { client_id: u32, char_id: u32 },
+}
+
+fn main() {
+    let parse = |code, args: Vec<&str>| ClientCommand::from_protocol(code, args.into_iter());
+
+    assert_eq!(parse("AM", vec!["2"]).unwrap(), ClientCommand::MusicList(2));
+    assert_eq!(parse("AM", vec![]).unwrap(), ClientCommand::AO2MusicList);
+    assert!(parse("AM", vec!["2", "3"]).is_err());
+    assert!(parse("AM", vec!["page"]).is_err());
+    assert_eq!(
+        parse("CC", vec!["0", "5", "hdid"]).unwrap(),
+        ClientCommand::SelectCharacter(0, 5, "hdid".into())
+    );
+    assert_eq!(
+        parse("CC", vec!["0", "5"]).unwrap(),
+        ClientCommand::SelectCharacterNoHdid { client_id: 0, char_id: 5 }
+    );
+}
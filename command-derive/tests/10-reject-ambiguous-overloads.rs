@@ -0,0 +1,12 @@
+use command_derive::*;
+
+#[derive(Command)]
+enum ClientRequest {
+    #[command(code = "AM")]
+    MusicList(u32),
+
+    #[command(code = "AM")]
+    AreaList(String),
+}
+
+fn main() {}
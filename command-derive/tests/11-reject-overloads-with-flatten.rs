@@ -0,0 +1,18 @@
+use command_derive::*;
+
+#[derive(Command)]
+enum ClientRequest {
+    #[command(code = "EE")]
+    EditEvidence(u32, #[command(flatten)] EvidenceArgs),
+
+    #[command(code = "EE")]
+    EditEvidenceName(u32, String),
+}
+
+#[derive(WithStrIter)]
+struct EvidenceArgs {
+    name: String,
+    description: String,
+}
+
+fn main() {}
@@ -0,0 +1,78 @@
+use command_derive::*;
+
+#[derive(Debug, Command, PartialEq)]
+pub enum ClientCommand {
+    #[command(code = "ZZ")]
+    CallModButton(Option<String>),
+
+    #[command(code = "MC")]
+    PlaySong(String, i32, Option<String>, Option<u32>),
+
+    #[command(code = "SC")]
+    CharacterList(Vec<String>),
+
+    #[command(code = "LE")]
+    EvidenceList { area: u32, items: Vec<String> },
+
+    #[command(code = "PE")]
+    AddEvidence(#[command(flatten)] EvidenceArgs),
+}
+
+#[derive(Debug, PartialEq, WithStrIter)]
+pub struct EvidenceArgs {
+    pub name: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+}
+
+fn parse(code: &str, args: Vec<&str>) -> Result<ClientCommand, anyhow::Error> {
+    ClientCommand::from_protocol(code, args.into_iter())
+}
+
+fn main() {
+    assert_eq!(parse("ZZ", vec![]).unwrap(), ClientCommand::CallModButton(None));
+    assert_eq!(
+        parse("ZZ", vec!["help"]).unwrap(),
+        ClientCommand::CallModButton(Some("help".into()))
+    );
+    assert!(parse("ZZ", vec!["help", "me"]).is_err());
+
+    let song = parse("MC", vec!["Trial.opus", "3"]).unwrap();
+    assert_eq!(song, ClientCommand::PlaySong("Trial.opus".into(), 3, None, None));
+    assert_eq!(song.extract_args(), vec!["Trial.opus", "3"]);
+    let song = parse("MC", vec!["Trial.opus", "3", "Nick", "2"]).unwrap();
+    assert_eq!(
+        song,
+        ClientCommand::PlaySong("Trial.opus".into(), 3, Some("Nick".into()), Some(2))
+    );
+    assert_eq!(song.extract_args(), vec!["Trial.opus", "3", "Nick", "2"]);
+    assert!(parse("MC", vec!["Trial.opus", "3", "Nick", "loud"]).is_err());
+    assert!(parse("MC", vec!["Trial.opus"]).is_err());
+
+    let chars = parse("SC", vec!["Phoenix", "Maya"]).unwrap();
+    assert_eq!(chars.to_message(), "SC#Phoenix#Maya#%");
+    assert_eq!(parse("SC", vec![]).unwrap(), ClientCommand::CharacterList(vec![]));
+    assert_eq!(
+        parse("LE", vec!["1"]).unwrap(),
+        ClientCommand::EvidenceList { area: 1, items: vec![] }
+    );
+
+    let evidence = parse("PE", vec!["Badge", "Shiny", "gold", "round"]).unwrap();
+    assert_eq!(
+        evidence,
+        ClientCommand::AddEvidence(EvidenceArgs {
+            name: "Badge".into(),
+            description: Some("Shiny".into()),
+            tags: vec!["gold".into(), "round".into()],
+        })
+    );
+    assert_eq!(evidence.to_message(), "PE#Badge#Shiny#gold#round#%");
+    assert_eq!(
+        parse("PE", vec!["Badge"]).unwrap(),
+        ClientCommand::AddEvidence(EvidenceArgs {
+            name: "Badge".into(),
+            description: None,
+            tags: vec![],
+        })
+    );
+}
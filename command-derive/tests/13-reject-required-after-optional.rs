@@ -0,0 +1,9 @@
+use command_derive::*;
+
+#[derive(Command)]
+enum ClientRequest {
+    #[command(code = "MC")]
+    PlaySong(String, Option<String>, u32),
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use command_derive::*;
+
+#[derive(WithStrIter)]
+struct Args {
+    names: Vec<String>,
+    id: u32,
+}
+
+fn main() {}
@@ -0,0 +1,57 @@
+use command_derive::*;
+
+mod hex {
+    pub fn from_arg(arg: &str) -> Result<u32, std::num::ParseIntError> {
+        u32::from_str_radix(arg, 16)
+    }
+
+    pub fn to_arg(value: &u32) -> String {
+        format!("{:x}", value)
+    }
+}
+
+#[derive(Debug, Command, PartialEq)]
+pub enum ClientCommand {
+    #[command(code = "COLOR")]
+    Color(#[command(with = "hex")] u32, #[command(with = "command_derive::ao_bool")] Option<bool>),
+
+    #[command(code = "FLAGS")]
+    Flags {
+        #[command(with = "command_derive::ao_bool")]
+        flags: Vec<bool>,
+    },
+
+    #[command(code = "SETCASE")]
+    SetCase(String, #[command(flatten)] CasePreferences),
+}
+
+#[derive(Debug, PartialEq, WithStrIter)]
+pub struct CasePreferences {
+    #[command(with = "command_derive::ao_bool")]
+    pub cm: bool,
+    #[command(with = "command_derive::ao_bool")]
+    pub def: bool,
+}
+
+fn parse(code: &str, args: Vec<&str>) -> Result<ClientCommand, anyhow::Error> {
+    ClientCommand::from_protocol(code, args.into_iter())
+}
+
+fn main() {
+    let color = parse("COLOR", vec!["ff", "1"]).unwrap();
+    assert_eq!(color, ClientCommand::Color(255, Some(true)));
+    assert_eq!(color.to_message(), "COLOR#ff#1#%");
+    assert!(parse("COLOR", vec!["zz"]).is_err());
+    assert!(parse("COLOR", vec!["ff", "true"]).is_err());
+
+    let flags = parse("FLAGS", vec!["1", "0", "1"]).unwrap();
+    assert_eq!(flags, ClientCommand::Flags { flags: vec![true, false, true] });
+    assert_eq!(flags.to_message(), "FLAGS#1#0#1#%");
+
+    let case = parse("SETCASE", vec!["Turnabout", "0", "1"]).unwrap();
+    assert_eq!(
+        case,
+        ClientCommand::SetCase("Turnabout".into(), CasePreferences { cm: false, def: true })
+    );
+    assert_eq!(case.to_message(), "SETCASE#Turnabout#0#1#%");
+}
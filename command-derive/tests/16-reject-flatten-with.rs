@@ -0,0 +1,14 @@
+use command_derive::*;
+
+#[derive(Command)]
+enum ClientRequest {
+    #[command(code = "PE")]
+    AddEvidence(#[command(flatten, with = "command_derive::ao_bool")] Args),
+}
+
+#[derive(WithStrIter)]
+struct Args {
+    name: String,
+}
+
+fn main() {}
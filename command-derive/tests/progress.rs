@@ -9,4 +9,12 @@ fn tests() {
     t.compile_fail("tests/06-reject-wrong-code-lit.rs");
     t.compile_fail("tests/07-reject-double-key.rs");
     t.pass("tests/08-accept-no-handler.rs");
+    t.pass("tests/09-accept-overloads.rs");
+    t.compile_fail("tests/10-reject-ambiguous-overloads.rs");
+    t.compile_fail("tests/11-reject-overloads-with-flatten.rs");
+    t.pass("tests/12-accept-optional-and-variadic.rs");
+    t.compile_fail("tests/13-reject-required-after-optional.rs");
+    t.compile_fail("tests/14-reject-variadic-not-last.rs");
+    t.pass("tests/15-accept-with.rs");
+    t.compile_fail("tests/16-reject-flatten-with.rs");
 }
@@ -0,0 +1,8 @@
+-- Ban IDs are generated by the database, bans may last for hours
+CREATE SEQUENCE IF NOT EXISTS bans_ban_id_seq OWNED BY bans.ban_id;
+ALTER TABLE bans ALTER COLUMN ban_id SET DEFAULT nextval('bans_ban_id_seq');
+SELECT setval('bans_ban_id_seq', COALESCE(MAX(ban_id), 0) + 1, false) FROM bans;
+ALTER TABLE bans ALTER COLUMN ban_date TYPE TIMESTAMP;
+ALTER TABLE bans ALTER COLUMN unban_date TYPE TIMESTAMP;
+
+UPDATE general_info SET db_version = 4;
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Types of `misc_events`, their IDs are used by the server
+INSERT INTO misc_event_types (type_id, type_name) VALUES
+	(1, 'system'),
+	(2, 'kick'),
+	(3, 'ban'),
+	(4, 'unban')
+ON CONFLICT DO NOTHING;
+
+UPDATE general_info SET db_version = 5;
\ No newline at end of file
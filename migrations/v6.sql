@@ -0,0 +1,25 @@
+-- `type_name` was meant to be a text, room event IDs are generated by the
+-- database
+ALTER TABLE room_event_types ALTER COLUMN type_name TYPE TEXT;
+INSERT INTO room_event_types (type_id, type_name) VALUES
+	(1, 'ooc'),
+	(2, 'wtce'),
+	(3, 'penalty'),
+	(4, 'roll'),
+	(5, 'notecard'),
+	(6, 'notecard_reveal'),
+	(7, 'rolla'),
+	(8, 'coinflip'),
+	(9, 'blockdj'),
+	(10, 'unblockdj'),
+	(11, 'disemvowel'),
+	(12, 'undisemvowel'),
+	(13, 'shake'),
+	(14, 'unshake'),
+	(15, 'evidence')
+ON CONFLICT DO NOTHING;
+CREATE SEQUENCE IF NOT EXISTS room_events_event_id_seq OWNED BY room_events.event_id;
+ALTER TABLE room_events ALTER COLUMN event_id SET DEFAULT nextval('room_events_event_id_seq');
+SELECT setval('room_events_event_id_seq', COALESCE(MAX(event_id), 0) + 1, false) FROM room_events;
+
+UPDATE general_info SET db_version = 6;
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Modcalls are stored as `misc_events`. Events keep the time of day they
+-- happened at, not just the date.
+INSERT INTO misc_event_types (type_id, type_name) VALUES
+	(5, 'modcall')
+ON CONFLICT DO NOTHING;
+ALTER TABLE misc_events ALTER COLUMN event_time TYPE TIMESTAMP;
+ALTER TABLE room_events ALTER COLUMN event_time TYPE TIMESTAMP;
+ALTER TABLE login_events ALTER COLUMN event_time TYPE TIMESTAMP;
+ALTER TABLE ic_events ALTER COLUMN event_time TYPE TIMESTAMP;
+ALTER TABLE connect_events ALTER COLUMN event_time TYPE TIMESTAMP;
+
+UPDATE general_info SET db_version = 7;
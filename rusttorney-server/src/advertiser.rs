@@ -0,0 +1,174 @@
+use crate::config::Config;
+use crate::master_server_client::Backoff;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::delay_for;
+
+/// What server lists get to know about the server
+#[derive(Debug, Serialize)]
+struct ServerInfo<'a> {
+    name: &'a str,
+    description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<&'a str>,
+    port: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ws_port: Option<u32>,
+    players: u8,
+    player_limit: u8,
+}
+
+impl<'a> ServerInfo<'a> {
+    fn new(config: &'a Config, players: u8) -> Self {
+        let general = &config.general;
+        Self {
+            name: &config.masterserver.name,
+            description: &config.masterserver.description,
+            ip: config.masterserver.public_ip.as_deref(),
+            port: general.port,
+            ws_port: general.websocket_port.filter(|_| general.use_websockets),
+            players,
+            player_limit: general.playerlimit,
+        }
+    }
+}
+
+/// Keeps the server listed by POSTing its info to `advertise_url`, every
+/// `advertise_interval` seconds and whenever the player count changes.
+/// Failed POSTs are retried with exponential backoff.
+pub async fn advertise(config: Arc<Config>, players: watch::Receiver<u8>) {
+    let backoff =
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(5 * 60));
+    advertise_with(config, players, backoff).await
+}
+
+async fn advertise_with(
+    config: Arc<Config>,
+    mut players: watch::Receiver<u8>,
+    mut backoff: Backoff,
+) {
+    let client = reqwest::Client::new();
+    let interval = Duration::from_secs(config.masterserver.advertise_interval);
+    let mut count = *players.borrow();
+    // The sender lives as long as the client manager, but just in case
+    let mut watching = true;
+
+    loop {
+        if let Err(e) = post(&client, &config, count).await {
+            let delay = backoff.next_delay();
+            log::warn!(
+                "Failed to advertise the server, retrying in {:?}: {}",
+                delay,
+                e
+            );
+            delay_for(delay).await;
+            count = *players.borrow();
+            continue;
+        }
+        backoff.reset();
+
+        if !watching {
+            delay_for(interval).await;
+            continue;
+        }
+        tokio::select! {
+            _ = delay_for(interval) => {}
+            changed = players.recv() => match changed {
+                Some(new_count) => count = new_count,
+                None => watching = false,
+            },
+        }
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    config: &Config,
+    players: u8,
+) -> Result<(), anyhow::Error> {
+    client
+        .post(&config.masterserver.advertise_url)
+        .json(&ServerInfo::new(config, players))
+        .send()
+        .await?
+        .error_for_status()?;
+    log::debug!("Advertised the server with {} players", players);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::test_config;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    /// Accepts one request, answers it with `status` and returns its body
+    async fn respond(listener: &mut TcpListener, status: &str) -> String {
+        let accepted = timeout(Duration::from_secs(5), listener.accept());
+        let (mut socket, _) = accepted.await.unwrap().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let body_start = loop {
+            let read = socket.read(&mut buf).await.unwrap();
+            assert_ne!(read, 0, "Request ended too early");
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(headers_end) = text.find("\r\n\r\n") {
+                let content_length = text[..headers_end]
+                    .lines()
+                    .find_map(|line| {
+                        let line = line.to_lowercase();
+                        let len = line.strip_prefix("content-length:")?;
+                        len.trim().parse::<usize>().ok()
+                    })
+                    .unwrap();
+                if request.len() >= headers_end + 4 + content_length {
+                    break headers_end + 4;
+                }
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request[body_start..].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn advertise_to_local_server_list() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = test_config();
+        config.masterserver.advertise_url =
+            format!("http://{}/servers", listener.local_addr().unwrap());
+        let (players_tx, players_rx) = watch::channel(0);
+        let backoff =
+            Backoff::new(Duration::from_millis(10), Duration::from_millis(10));
+        tokio::spawn(advertise_with(Arc::new(config), players_rx, backoff));
+
+        // Failed POST is retried
+        respond(&mut listener, "500 Internal Server Error").await;
+        let body = respond(&mut listener, "200 OK").await;
+        let info: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "name": "My server",
+                "description": "My server description!",
+                "port": 27016,
+                "ws_port": 50001,
+                "players": 0,
+                "player_limit": 100,
+            })
+        );
+
+        players_tx.broadcast(3).unwrap();
+        let body = respond(&mut listener, "200 OK").await;
+        let info: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(info["players"], 3);
+    }
+}
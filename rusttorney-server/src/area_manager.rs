@@ -0,0 +1,390 @@
+use crate::client_manager::Privilege;
+use crate::command::{EvidenceArgs, ServerCommand};
+use crate::config::{AreaConfig, EvidenceMode, SongConfig};
+use crate::networking::codec::escape;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Area {
+    pub(crate) id: usize,
+    pub(crate) name: String,
+    pub(crate) background: String,
+    /// Locked areas can't be entered, unless they are spectatable
+    pub(crate) locked: bool,
+    /// Clients may enter a locked spectatable area, but can't talk in IC
+    pub(crate) spectatable: bool,
+    pub(crate) evidence_mode: EvidenceMode,
+    pub(crate) max_players: Option<u8>,
+    /// IDs of the clients which are currently in this area
+    pub(crate) clients: HashSet<u8>,
+    /// Last song played in this area
+    pub(crate) song: Option<PlayingSong>,
+    /// IDs of the clients managing the case in this area
+    pub(crate) case_managers: HashSet<u8>,
+    pub(crate) evidence: Vec<EvidenceArgs>,
+    /// Health of the defense and the prosecution
+    pub(crate) penalties: [u32; 2],
+}
+
+/// Song played in an area, so that clients entering it hear it as well
+#[derive(Debug, Clone)]
+pub struct PlayingSong {
+    pub(crate) name: String,
+    pub(crate) looping: bool,
+    length: Option<u32>,
+    started_at: Instant,
+}
+
+impl PlayingSong {
+    /// Clients stop the music when asked to play this
+    pub const STOP: &'static str = "~stop.mp3";
+
+    pub fn new(song: &SongConfig) -> Self {
+        Self {
+            name: song.name.clone(),
+            looping: song.looping,
+            length: song.length,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Looping songs play forever, others until their length runs out.
+    /// Songs of unknown length are assumed to be still playing.
+    pub fn is_playing(&self) -> bool {
+        match self.length {
+            Some(length) if !self.looping => {
+                self.started_at.elapsed() < Duration::from_secs(length as u64)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Area {
+    fn new(id: usize, config: &AreaConfig) -> Self {
+        Self {
+            id,
+            name: config.name.clone(),
+            background: config.background.clone(),
+            locked: config.locked,
+            spectatable: config.spectatable,
+            evidence_mode: config.evidence_mode,
+            max_players: config.max_players,
+            clients: HashSet::new(),
+            song: None,
+            case_managers: HashSet::new(),
+            evidence: Vec::new(),
+            penalties: [Self::MAX_PENALTY; 2],
+        }
+    }
+
+    /// Health bars go from 0 to this, starting full
+    pub const MAX_PENALTY: u32 = 10;
+
+    /// Sets health bar of the defense (`1`) or the prosecution (`2`)
+    pub fn set_penalty(
+        &mut self,
+        bar: u32,
+        value: u32,
+    ) -> Result<(), anyhow::Error> {
+        if value > Self::MAX_PENALTY {
+            anyhow::bail!("Penalty {} is out of range", value);
+        }
+        match bar {
+            1 | 2 => self.penalties[bar as usize - 1] = value,
+            _ => anyhow::bail!("No such health bar: {}", bar),
+        }
+        Ok(())
+    }
+
+    /// `HP` commands for both health bars
+    pub fn penalties(&self) -> impl Iterator<Item = ServerCommand> + '_ {
+        self.penalties
+            .iter()
+            .zip(1..)
+            .map(|(&value, bar)| ServerCommand::Penalties(bar, value))
+    }
+
+    /// Checks the area's evidence mode
+    pub fn can_edit_evidence(
+        &self,
+        client_id: u8,
+        privilege: Privilege,
+    ) -> bool {
+        match self.evidence_mode {
+            EvidenceMode::Anyone => true,
+            EvidenceMode::CaseManagers => {
+                self.case_managers.contains(&client_id)
+                    || privilege >= Privilege::Moderator
+            }
+            EvidenceMode::Mods => privilege >= Privilege::Moderator,
+        }
+    }
+
+    /// `LE` with the evidence of this area
+    pub fn evidence_list(&self) -> ServerCommand {
+        ServerCommand::EvidenceList(self.evidence_entries().collect())
+    }
+
+    /// `LE` with `page_len` pieces of evidence, for clients which ask for
+    /// the list page by page
+    pub fn evidence_page(&self, page: usize, page_len: usize) -> ServerCommand {
+        let evidence =
+            self.evidence_entries().skip(page * page_len).take(page_len);
+        ServerCommand::EvidenceList(evidence.collect())
+    }
+
+    fn evidence_entries(&self) -> impl Iterator<Item = String> + '_ {
+        self.evidence.iter().map(|e| {
+            format!(
+                "{}&{}&{}",
+                escape(&e.name),
+                escape(&e.description),
+                escape(&e.image)
+            )
+        })
+    }
+
+    /// Song which a client entering this area should hear
+    pub fn current_song(&self) -> Option<&PlayingSong> {
+        self.song.as_ref().filter(|song| song.is_playing())
+    }
+
+    pub fn is_full(&self) -> bool {
+        match self.max_players {
+            Some(max) => self.clients.len() >= max as usize,
+            None => false,
+        }
+    }
+
+    /// Checks whether a client may enter this area. Moderators may enter
+    /// any area.
+    pub fn can_join(&self, privilege: Privilege) -> Result<(), anyhow::Error> {
+        if privilege >= Privilege::Moderator {
+            return Ok(());
+        }
+        if self.locked && !self.spectatable {
+            anyhow::bail!("Area {} is locked!", self.name);
+        }
+        if self.is_full() {
+            anyhow::bail!("Area {} is full!", self.name);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps track of all the areas and which clients are in which area
+#[derive(Debug)]
+pub struct AreaManager {
+    areas: Vec<Area>,
+}
+
+impl AreaManager {
+    /// The area in which new clients end up
+    pub const DEFAULT_AREA: usize = 0;
+
+    pub fn new(configs: &[AreaConfig]) -> Self {
+        let areas = configs
+            .iter()
+            .enumerate()
+            .map(|(id, config)| Area::new(id, config))
+            .collect();
+
+        Self { areas }
+    }
+
+    pub fn get(&self, area_id: usize) -> Option<&Area> {
+        self.areas.get(area_id)
+    }
+
+    pub fn get_mut(&mut self, area_id: usize) -> Option<&mut Area> {
+        self.areas.get_mut(area_id)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Area> {
+        self.areas.iter().find(|area| area.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Area> {
+        self.areas.iter()
+    }
+
+    /// Puts client in the area, without checking whether it is locked or
+    /// full
+    pub fn add_client(&mut self, area_id: usize, client_id: u8) {
+        if let Some(area) = self.areas.get_mut(area_id) {
+            area.clients.insert(client_id);
+        }
+    }
+
+    /// Takes client out of the area, along with its case manager role
+    pub fn remove_client(&mut self, area_id: usize, client_id: u8) {
+        if let Some(area) = self.areas.get_mut(area_id) {
+            area.clients.remove(&client_id);
+            area.case_managers.remove(&client_id);
+        }
+    }
+
+    /// Moves client from one area to another, if the latter one allows that
+    pub fn move_client(
+        &mut self,
+        client_id: u8,
+        from: usize,
+        to: usize,
+        privilege: Privilege,
+    ) -> Result<(), anyhow::Error> {
+        if from == to {
+            anyhow::bail!("You are already in this area!");
+        }
+        self.areas
+            .get(to)
+            .ok_or_else(|| anyhow::anyhow!("No such area: {}", to))?
+            .can_join(privilege)?;
+
+        self.remove_client(from, client_id);
+        self.add_client(to, client_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area_config(name: &str) -> AreaConfig {
+        AreaConfig {
+            name: name.into(),
+            background: "gs4".into(),
+            locked: false,
+            spectatable: false,
+            evidence_mode: EvidenceMode::Anyone,
+            max_players: None,
+        }
+    }
+
+    #[test]
+    fn move_between_areas() {
+        let mut manager =
+            AreaManager::new(&[area_config("Basement"), area_config("Lobby")]);
+        manager.add_client(AreaManager::DEFAULT_AREA, 3);
+
+        manager.move_client(3, 0, 1, Privilege::Player).unwrap();
+
+        assert!(manager.get(0).unwrap().clients.is_empty());
+        assert!(manager.get(1).unwrap().clients.contains(&3));
+        assert_eq!(manager.by_name("Lobby").unwrap().id, 1);
+    }
+
+    #[test]
+    fn reject_locked_and_full_areas() {
+        let mut locked = area_config("Locked");
+        locked.locked = true;
+        let mut spectatable = area_config("Spectatable");
+        spectatable.locked = true;
+        spectatable.spectatable = true;
+        let mut full = area_config("Full");
+        full.max_players = Some(1);
+        let mut manager = AreaManager::new(&[
+            area_config("Basement"),
+            locked,
+            spectatable,
+            full,
+        ]);
+        manager.add_client(0, 1);
+        manager.add_client(3, 2);
+
+        let player = Privilege::Player;
+        assert!(manager.move_client(1, 0, 1, player).is_err());
+        assert!(manager.move_client(1, 0, 3, player).is_err());
+        assert!(manager.move_client(1, 0, 0, player).is_err());
+        assert!(manager.move_client(1, 0, 42, player).is_err());
+        assert!(manager.get(0).unwrap().clients.contains(&1));
+
+        manager.move_client(1, 0, 2, player).unwrap();
+        assert!(manager.get(2).unwrap().clients.contains(&1));
+
+        let moderator = Privilege::Moderator;
+        manager.move_client(1, 2, 1, moderator).unwrap();
+        manager.move_client(1, 1, 3, moderator).unwrap();
+        assert!(manager.move_client(1, 3, 42, moderator).is_err());
+        assert!(manager.get(3).unwrap().clients.contains(&1));
+    }
+
+    #[test]
+    fn songs_stop_playing() {
+        let song = |length, looping| SongConfig {
+            name: "Objection.opus".into(),
+            length,
+            looping,
+        };
+        let mut area = Area::new(0, &area_config("Basement"));
+        assert!(area.current_song().is_none());
+
+        area.song = Some(PlayingSong::new(&song(Some(0), true)));
+        assert!(area.current_song().is_some());
+        area.song = Some(PlayingSong::new(&song(None, false)));
+        assert!(area.current_song().is_some());
+        area.song = Some(PlayingSong::new(&song(Some(0), false)));
+        assert!(area.current_song().is_none());
+    }
+
+    #[test]
+    fn evidence_permissions() {
+        let mut cm_only = area_config("Courtroom");
+        cm_only.evidence_mode = EvidenceMode::CaseManagers;
+        let mut mods_only = area_config("Mod room");
+        mods_only.evidence_mode = EvidenceMode::Mods;
+        let mut manager =
+            AreaManager::new(&[area_config("Basement"), cm_only, mods_only]);
+        manager.add_client(1, 1);
+        manager.get_mut(1).unwrap().case_managers.insert(1);
+        let (player, moderator) = (Privilege::Player, Privilege::Moderator);
+
+        assert!(manager.get(0).unwrap().can_edit_evidence(2, player));
+        assert!(manager.get(1).unwrap().can_edit_evidence(1, player));
+        assert!(!manager.get(1).unwrap().can_edit_evidence(2, player));
+        assert!(manager.get(1).unwrap().can_edit_evidence(2, moderator));
+        assert!(!manager.get(2).unwrap().can_edit_evidence(1, player));
+        assert!(manager.get(2).unwrap().can_edit_evidence(1, moderator));
+
+        manager.move_client(1, 1, 0, player).unwrap();
+        assert!(manager.get(1).unwrap().case_managers.is_empty());
+    }
+
+    #[test]
+    fn page_evidence() {
+        let mut area = Area::new(0, &area_config("Courtroom"));
+        area.evidence = (0..3)
+            .map(|i| EvidenceArgs {
+                name: format!("Knife #{}", i),
+                description: "Sharp".into(),
+                image: "knife.png".into(),
+            })
+            .collect();
+
+        let page = |page| match area.evidence_page(page, 2) {
+            ServerCommand::EvidenceList(evidence) => evidence,
+            other => panic!("Unexpected command: {:?}", other),
+        };
+        assert_eq!(
+            page(0),
+            ["Knife <num>0&Sharp&knife.png", "Knife <num>1&Sharp&knife.png"]
+        );
+        assert_eq!(page(1), ["Knife <num>2&Sharp&knife.png"]);
+        assert!(page(2).is_empty());
+    }
+
+    #[test]
+    fn validate_penalties() {
+        let mut area = Area::new(0, &area_config("Courtroom"));
+        assert_eq!(area.penalties, [10, 10]);
+
+        area.set_penalty(1, 3).unwrap();
+        area.set_penalty(2, 0).unwrap();
+        assert!(area.set_penalty(2, 11).is_err());
+        assert!(area.set_penalty(0, 5).is_err());
+        assert!(area.set_penalty(3, 5).is_err());
+        assert_eq!(area.penalties, [3, 0]);
+    }
+}
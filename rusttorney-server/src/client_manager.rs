@@ -1,17 +1,15 @@
-use futures::stream::SplitSink;
-use futures::SinkExt;
-use std::collections::{BinaryHeap, HashSet};
+use std::collections::{BinaryHeap, HashMap};
 
-use crate::command::ServerCommand;
+use crate::area_manager::AreaManager;
+use crate::command::{CasePreferences, ICMessageArgs, ServerCommand};
+use crate::config::Config;
+use crate::features::{FeatureSet, Version};
+use crate::floodguard::FloodGuard;
 use crate::networking::codec::AOMessageCodec;
-use crate::networking::database::DbWrapper;
 use futures::channel::mpsc;
-use std::hash::{Hash, Hasher};
-use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio_util::codec::Framed;
+use std::time::Duration;
+use tokio::sync::watch;
 
 #[allow(unused)]
 #[derive(Debug, Clone, Default)]
@@ -19,86 +17,505 @@ pub struct Client {
     is_checked: bool,
     pub(crate) hdid: String,
     pub(crate) id: u8,
+    /// -1 for spectators
     pub(crate) char_id: i32,
-    // area: AreaManager,
-    name: String,
+    pub(crate) area: usize,
+    /// Name used in OOC
+    pub(crate) name: String,
     fake_name: String,
-    is_mod: bool,
+    pub(crate) is_mod: bool,
     pub(crate) ipid: u32,
+    /// Last IC message sent by this client, used for pairing
+    pub(crate) last_ic: Option<ICMessageArgs>,
+    pub(crate) music_floodguard: FloodGuard,
+    pub(crate) wtce_floodguard: FloodGuard,
+    pub(crate) modcall_floodguard: FloodGuard,
+    /// Cases the client is interested in, as sent in `SETCASE`
+    pub(crate) cases: String,
+    /// Roles the client wants to be notified about
+    pub(crate) case_preferences: CasePreferences,
+    /// Client software and version, as sent in `ID`
+    pub(crate) software: String,
+    pub(crate) version: Option<Version>,
+    pub(crate) features: FeatureSet,
     // TODO: other fields
 }
 
-/// We hash and eq clients only by ipid
-impl Hash for Client {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.ipid.hash(state);
+impl Client {
+    pub fn new(user_id: u8, ipid: u32, config: &Config) -> Self {
+        Self {
+            id: user_id,
+            ipid,
+            char_id: -1,
+            music_floodguard: FloodGuard::new(&config.music_change_floodguard),
+            wtce_floodguard: FloodGuard::new(&config.wtce_floodguard),
+            modcall_floodguard: FloodGuard::new(&config.modcall_floodguard),
+            ..Default::default()
+        }
     }
-}
 
-impl PartialEq for Client {
-    fn eq(&self, other: &Self) -> bool {
-        self.ipid == other.ipid
+    pub fn privilege(&self) -> Privilege {
+        if self.is_mod {
+            Privilege::Moderator
+        } else {
+            Privilege::Player
+        }
     }
 }
 
-impl Eq for Client {}
-
-impl Client {
-    pub fn new(user_id: u8, ipid: u32) -> Self {
-        Self { id: user_id, ipid, ..Default::default() }
-    }
+/// What a client is allowed to do, from the least to the most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    Player,
+    Moderator,
 }
 
+/// Outbound half of a client connection. Everything sent here is written to
+/// the client's socket by its writer task.
+pub type ClientSender = mpsc::UnboundedSender<ServerCommand>;
+
 pub struct ClientManager {
-    pub(crate) clients: HashSet<Client>,
-    // config: Config<'a>,
+    pub(crate) clients: HashMap<u8, Client>,
+    senders: HashMap<u8, ClientSender>,
+    pub(crate) areas: AreaManager,
+    config: Arc<Config>,
     cur_id: BinaryHeap<u8>,
-    db: DbWrapper,
+    /// Notifies about changes of [`player_count`](Self::player_count)
+    player_count_tx: watch::Sender<u8>,
+    player_count_rx: watch::Receiver<u8>,
+    /// `/login` attempts by IPID, kept across reconnects and multiclients
+    login_guards: HashMap<u32, FloodGuard>,
 }
 
 impl ClientManager {
-    pub fn new(playerlimit: u8, db: DbWrapper) -> Self {
-        let cur_id = (0..playerlimit).collect();
+    pub fn new(config: Arc<Config>) -> Self {
+        let cur_id = (0..config.general.playerlimit).collect();
+        let (player_count_tx, player_count_rx) = watch::channel(0);
         Self {
-            clients: HashSet::new(),
-            // config,
+            clients: HashMap::new(),
+            senders: HashMap::new(),
+            areas: AreaManager::new(&config.areas),
+            config,
             cur_id,
-            db,
+            player_count_tx,
+            player_count_rx,
+            login_guards: HashMap::new(),
         }
     }
 
-    pub async fn new_client(
+    /// Records a `/login` attempt from the IPID. Fails with the time left
+    /// until it may try again if the IPID tried too often.
+    pub fn check_login(&mut self, ipid: u32) -> Result<(), Duration> {
+        let config = &self.config.login_floodguard;
+        self.login_guards
+            .entry(ipid)
+            .or_insert_with(|| FloodGuard::new(config))
+            .check()
+    }
+
+    /// Registers a client connected from the IPID, which was already
+    /// checked for bans
+    pub fn new_client(
         &mut self,
-        socket: &mut Framed<TcpStream, AOMessageCodec>,
-        ip: IpAddr,
+        sender: ClientSender,
+        ipid: u32,
     ) -> Result<Client, anyhow::Error> {
-        // TODO: GeoIP
-        {}
-
-        // TODO: bans
-        {}
+        if self.is_multiclient_limit_reached(ipid) {
+            sender.unbounded_send(ServerCommand::BanReason(format!(
+                "You can't have more than {} clients connected at once.",
+                self.config.multiclient_limit
+            )))?;
+            anyhow::bail!("IPID {} has too many clients", ipid);
+        }
 
         let user_id = match self.cur_id.pop() {
             Some(uid) => uid,
             None => {
-                socket
-                    .send(ServerCommand::BanReason(
-                        "This server is full.".into(),
-                    ))
-                    .await?;
+                sender.unbounded_send(ServerCommand::BanReason(
+                    "This server is full.".into(),
+                ))?;
                 anyhow::bail!("This server is full!");
             }
         };
-        let ipid = self.db.ipid(ip).await? as u32;
 
-        let client = Client::new(user_id, ipid);
-        // We have to clone here to store each client in a HashSet
-        self.clients.insert(client.clone());
+        let client = Client::new(user_id, ipid, &self.config);
+        self.areas.add_client(client.area, client.id);
+        // We have to clone here to store each client in a HashMap
+        self.clients.insert(client.id, client.clone());
+        self.senders.insert(client.id, sender);
 
         Ok(client)
     }
 
     pub fn update_client(&mut self, client: Client) {
-        self.clients.replace(client);
+        self.clients.insert(client.id, client);
+        self.update_player_count();
+    }
+
+    /// Forgets about disconnected client, freeing its ID, its place in the
+    /// area and its character
+    pub fn remove_client(&mut self, client: &Client) {
+        self.areas.remove_client(client.area, client.id);
+        self.clients.remove(&client.id);
+        self.senders.remove(&client.id);
+        self.cur_id.push(client.id);
+        self.update_player_count();
+
+        if client.char_id != -1 {
+            self.broadcast_area(client.area, self.chars_check(client.area));
+        }
+    }
+
+    /// Moves client to another area and updates stored copy of the client.
+    /// If its character is already taken there, the client becomes a
+    /// spectator.
+    pub fn move_client(
+        &mut self,
+        client: &mut Client,
+        area_id: usize,
+    ) -> Result<(), anyhow::Error> {
+        self.areas.move_client(
+            client.id,
+            client.area,
+            area_id,
+            client.privilege(),
+        )?;
+        let old_area = client.area;
+        client.area = area_id;
+        if self.is_char_taken(area_id, client.char_id, client.id) {
+            client.char_id = -1;
+        }
+        self.update_client(client.clone());
+
+        self.broadcast_area(old_area, self.chars_check(old_area));
+        self.broadcast_area(area_id, self.chars_check(area_id));
+        Ok(())
+    }
+
+    /// Makes client play as another character (-1 to spectate), releasing
+    /// the previous one
+    pub fn change_character(
+        &mut self,
+        client: &mut Client,
+        char_id: i32,
+    ) -> Result<(), anyhow::Error> {
+        if char_id != -1 && self.character_name(char_id).is_none() {
+            anyhow::bail!("No such character: {}", char_id);
+        }
+        if self.is_char_taken(client.area, char_id, client.id) {
+            anyhow::bail!("Character {} is already taken!", char_id);
+        }
+
+        client.char_id = char_id;
+        self.update_client(client.clone());
+        self.broadcast_area(client.area, self.chars_check(client.area));
+        Ok(())
+    }
+
+    /// Number of clients playing as a character, spectators aren't counted
+    pub fn player_count(&self) -> u8 {
+        self.clients.values().filter(|c| c.char_id != -1).count() as u8
+    }
+
+    /// Receives the player count every time it changes
+    pub fn watch_player_count(&self) -> watch::Receiver<u8> {
+        self.player_count_rx.clone()
+    }
+
+    fn update_player_count(&mut self) {
+        let count = self.player_count();
+        if *self.player_count_rx.borrow() != count {
+            // Receivers are optional, `player_count_rx` is always there
+            let _ = self.player_count_tx.broadcast(count);
+        }
+    }
+
+    pub fn character_name(&self, char_id: i32) -> Option<&str> {
+        if char_id < 0 {
+            return None;
+        }
+        self.config.characters.get(char_id as usize).map(String::as_str)
+    }
+
+    /// Whether anyone but `client_id` plays as the character in the area.
+    /// Spectators never take a character.
+    fn is_char_taken(
+        &self,
+        area_id: usize,
+        char_id: i32,
+        client_id: u8,
+    ) -> bool {
+        char_id != -1
+            && self
+                .clients_in_area(area_id)
+                .any(|c| c.id != client_id && c.char_id == char_id)
+    }
+
+    /// `CharsCheck` telling which characters are taken in the area
+    pub fn chars_check(&self, area_id: usize) -> ServerCommand {
+        let mut taken = vec![0; self.config.characters.len()];
+        for client in self.clients_in_area(area_id).filter(|c| c.char_id != -1)
+        {
+            if let Some(flag) = taken.get_mut(client.char_id as usize) {
+                *flag = -1;
+            }
+        }
+        ServerCommand::TakenCharacters(taken)
+    }
+
+    pub fn clients_of_ipid(&self, ipid: u32) -> impl Iterator<Item = &Client> {
+        self.clients.values().filter(move |c| c.ipid == ipid)
+    }
+
+    /// Whether another client from the IPID would exceed `multiclient_limit`
+    pub fn is_multiclient_limit_reached(&self, ipid: u32) -> bool {
+        self.clients_of_ipid(ipid).count()
+            >= self.config.multiclient_limit as usize
+    }
+
+    pub fn clients_in_area(
+        &self,
+        area_id: usize,
+    ) -> impl Iterator<Item = &Client> {
+        self.clients.values().filter(move |c| c.area == area_id)
+    }
+
+    /// Sends command to a single client
+    pub fn send_to(
+        &self,
+        client_id: u8,
+        command: ServerCommand,
+    ) -> Result<(), anyhow::Error> {
+        let features = self
+            .clients
+            .get(&client_id)
+            .map(|client| client.features)
+            .unwrap_or_default();
+        self.senders
+            .get(&client_id)
+            .ok_or_else(|| anyhow::anyhow!("No client with ID {}", client_id))?
+            .unbounded_send(command.adapt_to(&features))
+            .map_err(Into::into)
+    }
+
+    /// Sends `command` to every client for which `predicate` returns `true`
+    /// and disconnects them afterwards. Returns IDs of these clients.
+    pub fn disconnect_filtered<P>(
+        &self,
+        mut predicate: P,
+        command: ServerCommand,
+    ) -> Vec<u8>
+    where
+        P: FnMut(&Client) -> bool,
+    {
+        let ids: Vec<_> = self
+            .clients
+            .values()
+            .filter(|c| predicate(c))
+            .map(|c| c.id)
+            .collect();
+        for id in &ids {
+            if let Some(sender) = self.senders.get(id) {
+                // Closing the channel stops the writer task once it has
+                // written everything, which ends the client's connection
+                let _ = sender.unbounded_send(command.clone());
+                sender.close_channel();
+            }
+        }
+        ids
+    }
+
+    /// Sends command to every connected client
+    pub fn broadcast(&self, command: ServerCommand) {
+        self.broadcast_filtered(|_| true, command)
+    }
+
+    /// Sends command to every client in the area
+    pub fn broadcast_area(&self, area_id: usize, command: ServerCommand) {
+        self.broadcast_filtered(|c| c.area == area_id, command)
+    }
+
+    /// Sends command to every client for which `predicate` returns `true`
+    pub fn broadcast_filtered<P>(
+        &self,
+        mut predicate: P,
+        command: ServerCommand,
+    ) where
+        P: FnMut(&Client) -> bool,
+    {
+        for client in self.clients.values().filter(|c| predicate(c)) {
+            if let Err(e) = self.send_to(client.id, command.clone()) {
+                // Client is disconnecting, it will be removed soon
+                log::debug!("Failed to send to client {}: {}", client.id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::test_config;
+    use crate::networking::Command;
+
+    fn manager() -> ClientManager {
+        ClientManager::new(Arc::new(test_config()))
+    }
+
+    /// Registers a client without touching the database
+    fn connect(
+        manager: &mut ClientManager,
+        id: u8,
+        area: usize,
+    ) -> mpsc::UnboundedReceiver<ServerCommand> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut client = Client::new(id, id as u32, &manager.config);
+        client.area = area;
+        manager.areas.add_client(area, id);
+        manager.clients.insert(id, client);
+        manager.senders.insert(id, sender);
+        receiver
+    }
+
+    fn received(
+        receiver: &mut mpsc::UnboundedReceiver<ServerCommand>,
+    ) -> Vec<String> {
+        let mut res = Vec::new();
+        while let Ok(command) = receiver.try_recv() {
+            res.push(command.to_message());
+        }
+        res
+    }
+
+    #[test]
+    fn broadcast_to_area() {
+        let mut manager = manager();
+        let mut rx1 = connect(&mut manager, 1, 0);
+        let mut rx2 = connect(&mut manager, 2, 0);
+        let mut rx3 = connect(&mut manager, 3, 1);
+
+        manager.broadcast_area(0, ServerCommand::KeepAlive);
+        manager.send_to(3, ServerCommand::Decryptor(34)).unwrap();
+
+        assert_eq!(received(&mut rx1), vec!["CHECK#%"]);
+        assert_eq!(received(&mut rx2), vec!["CHECK#%"]);
+        assert_eq!(received(&mut rx3), vec!["decryptor#34#%"]);
+    }
+
+    #[test]
+    fn broadcast_skips_removed_clients() {
+        let mut manager = manager();
+        let mut rx1 = connect(&mut manager, 1, 0);
+        let rx2 = connect(&mut manager, 2, 0);
+        let client = manager.clients[&2].clone();
+
+        drop(rx2);
+        manager.broadcast(ServerCommand::KeepAlive);
+        manager.remove_client(&client);
+        manager.broadcast_filtered(|c| c.id != 1, ServerCommand::KeepAlive);
+
+        assert_eq!(received(&mut rx1), vec!["CHECK#%"]);
+        assert!(manager.send_to(2, ServerCommand::KeepAlive).is_err());
+        assert!(manager.areas.get(0).unwrap().clients.contains(&1));
+    }
+
+    #[test]
+    fn reject_taken_characters() {
+        let mut manager = manager();
+        let mut rx1 = connect(&mut manager, 1, 0);
+        let _rx2 = connect(&mut manager, 2, 0);
+        let _rx3 = connect(&mut manager, 3, 1);
+        let mut client1 = manager.clients[&1].clone();
+        let mut client2 = manager.clients[&2].clone();
+        let mut client3 = manager.clients[&3].clone();
+
+        manager.change_character(&mut client1, 1).unwrap();
+        assert!(manager.change_character(&mut client2, 1).is_err());
+        assert!(manager.change_character(&mut client2, 3).is_err());
+        assert_eq!(client2.char_id, -1);
+        manager.change_character(&mut client3, 1).unwrap();
+
+        assert_eq!(received(&mut rx1), vec!["CharsCheck#0#-1#0#%"]);
+        assert_eq!(manager.clients[&1].char_id, 1);
+        assert!(manager.is_char_taken(0, 1, 2));
+        assert!(!manager.is_char_taken(0, 1, 1));
+    }
+
+    #[test]
+    fn release_characters() {
+        let mut manager = manager();
+        let mut rx1 = connect(&mut manager, 1, 0);
+        let _rx2 = connect(&mut manager, 2, 0);
+        let _rx3 = connect(&mut manager, 3, 1);
+        let mut client1 = manager.clients[&1].clone();
+        let mut client2 = manager.clients[&2].clone();
+        let mut client3 = manager.clients[&3].clone();
+        manager.change_character(&mut client1, 0).unwrap();
+        manager.change_character(&mut client2, 2).unwrap();
+        manager.change_character(&mut client3, 0).unwrap();
+        received(&mut rx1);
+
+        // Switching characters releases the old one
+        manager.change_character(&mut client2, 1).unwrap();
+        // Character 0 is taken in area 1, so client 3 has to spectate
+        manager.move_client(&mut client3, 0).unwrap();
+        assert_eq!(client3.char_id, -1);
+        manager.remove_client(&client1);
+
+        assert_eq!(
+            received(&mut rx1),
+            vec!["CharsCheck#-1#-1#0#%", "CharsCheck#-1#-1#0#%"]
+        );
+        assert_eq!(manager.chars_check(0).to_message(), "CharsCheck#0#-1#0#%");
+    }
+
+    #[test]
+    fn disconnect_clients() {
+        let mut manager = manager();
+        let mut rx1 = connect(&mut manager, 1, 0);
+        let mut rx2 = connect(&mut manager, 2, 1);
+        let mut rx3 = connect(&mut manager, 3, 0);
+        manager.clients.get_mut(&3).unwrap().ipid = 1;
+
+        let kicked = manager.disconnect_filtered(
+            |c| c.ipid == 1,
+            ServerCommand::Kicked("Spam".into()),
+        );
+
+        assert_eq!(kicked.len(), 2);
+        for rx in [&mut rx1, &mut rx3] {
+            assert_eq!(rx.try_recv().unwrap().to_message(), "KK#Spam#%");
+            assert_eq!(rx.try_recv().unwrap_err(), mpsc::TryRecvError::Closed);
+        }
+        assert_eq!(rx2.try_recv().unwrap_err(), mpsc::TryRecvError::Empty);
+        assert!(manager.send_to(1, ServerCommand::KeepAlive).is_err());
+    }
+
+    #[test]
+    fn limit_clients_per_ipid() {
+        let mut manager = manager();
+        for id in 0..manager.config.multiclient_limit {
+            assert!(!manager.is_multiclient_limit_reached(7));
+            connect(&mut manager, id, 0);
+            manager.clients.get_mut(&id).unwrap().ipid = 7;
+        }
+
+        assert!(manager.is_multiclient_limit_reached(7));
+        assert!(!manager.is_multiclient_limit_reached(8));
+        assert_eq!(
+            manager.clients_of_ipid(7).count(),
+            manager.config.multiclient_limit as usize
+        );
+    }
+
+    #[test]
+    fn throttle_logins_per_ipid() {
+        let mut manager = manager();
+        for _ in 0..manager.config.login_floodguard.times_per_interval {
+            assert!(manager.check_login(7).is_ok());
+        }
+
+        assert!(manager.check_login(7).is_err());
+        assert!(manager.check_login(8).is_ok());
     }
 }
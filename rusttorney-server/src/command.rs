@@ -1,102 +1,427 @@
-use crate::networking::{Command, WithStrIter};
-
-use std::{
-    fmt::{Debug, Display},
-    str::FromStr,
-};
-
-#[rustfmt::skip]
-#[derive(Debug, Command, PartialEq)]
-#[command(handler = "crate::server::AO2MessageHandler")]
-pub enum ClientCommand {
-    #[command(code = "HI", handle = "handle_handshake")]
-    Handshake(String),                           // HI#<hdid:String>#%
-    #[command(code = "ID", handle = "handle_client_version")]
-    ClientVersion(u32, String, String),          /* ID#<pv:u32>#<software:String>#
-                                                  * <version:String>#% */
-    #[command(code = "CH", handle = "handle_keepalive")]
-    KeepAlive(i32),                              // CH
-    #[command(code = "askchaa", handle = "handle_ask_list_lengths")]
-    AskListLengths,                              // askchaa
-    #[command(code = "askchar2", handle = "handle_ask_list_characters")]
-    AskListCharacters,                           // askchar
-    #[command(code = "AN", handle = "handle_character_list")]
-    CharacterList(u32),                          // AN#<page:u32>#%
-    #[command(code = "AE", handle = "handle_evidence_list")]
-    EvidenceList(u32),                           // AE#<page:u32>#%
-    #[command(code = "AM", handle = "handle_music_list")]
-    MusicList(u32),                              // AM#<page:u32>#%
-    #[command(code = "AC", handle = "handle_ao2_character_list")]
-    AO2CharacterList,                            // AC#%
-    #[command(code = "AM", handle = "handle_ao2_music_list")]
-    AO2MusicList,                                // AM#%
-    #[command(code = "RD", handle = "handle_ao2_ready")]
-    AO2Ready,                                    // RD#%
-    #[command(code = "CC", handle = "handle_select_character")]
-    SelectCharacter(u32, u32, String),           /* CC<client_id:u32>#
-                                                  * <char_id:u32#<hdid:
-                                                  * String>#% */
-    #[command(code = "MS", handle = "handle_ic_message")]
-    ICMessage,                                   // MS
-    #[command(code = "CT", handle = "handle_ooc_message")]
-    OOCMessage(String, String),                  /* CT#<name:String>#
-                                                  * <message:String>#% */
-    #[command(code = "MC", handle = "handle_play_song")]
-    PlaySong(u32, u32),                          // MC#<song_name:u32>#<???:u32>#%
-    #[command(code = "RT", handle = "handle_wtce_buttons")]
-    WTCEButtons(String),                         // RT#<type:String>#%
-    #[command(code = "SETCASE", handle = "handle_set_case_preferences")]                 /* SETCASE#<cases:String>#<will_cm:boolean>#<will_def:boolean>#<will_pro:boolean>#<will_judge:boolean>#<will_jury:boolean>#<will_steno:boolean>#% */
-    SetCasePreferences(String, #[command(flatten)] CasePreferences),
-    #[command(code = "CASEA", handle = "handle_case_announce")]                   // CASEA
-    CaseAnnounce(String, #[command(flatten)] CasePreferences),
-    #[command(code = "HP", handle = "handle_penalties")]
-    Penalties(u32, u32),                         /* HP#<type:u32>#
-                                                  * <new_value:u32>#% */
-    #[command(code = "PE", handle = "handle_add_evidence")]
-    AddEvidence(
-        #[command(flatten)] EvidenceArgs),       /* PE#<name:String>#<description:String>#
-                                                  * <image:String>#% */
-    #[command(code = "DE", handle = "handle_delete_evidence")]
-    DeleteEvidence(u32),                         // DE#<id:u32>#%
-    #[command(code = "EE", handle = "handle_edit_evidence")]
-    EditEvidence(u32, #[command(flatten)] EvidenceArgs),
-                                                 /* EE#<id:u32>#<name:String>#
-                                                  * <description:String>#<image:
-                                                  * String>#% */
-    #[command(code = "ZZ", handle = "handle_call_mod_button")]
-    CallModButton(String),                       // ZZ?#<reason:String>?#%
-}
-
-#[derive(Debug, PartialEq, WithStrIter)]
-pub struct EvidenceArgs {
-    pub name: String,
-    pub description: String,
-    pub image: String,
-}
-
-#[derive(Debug, PartialEq, WithStrIter)]
-pub struct CasePreferences {
-    pub cm: bool,
-    pub def: bool,
-    pub pro: bool,
-    pub judge: bool,
-    pub jury: bool,
-    pub steno: bool,
-}
-
-#[rustfmt::skip]
-#[derive(Debug, Command)]
-pub enum ServerCommand {
-    #[command(code = "HI")]
-    Handshake(String),                  // HI#<hdid:String>#%
-    #[command(code = "CHECK")]
-    KeepAlive,                          // CHECK#%
-    #[command(code = "decryptor")]
-    Decryptor(u32),                     // decryptor#<i:u32>#%
-    #[command(code = "BD")]
-    BanReason(String),                  // BD#<reason:String>#%,
-    #[command(code = "ID")]
-    ServerVersion(u8, String, String),  // ID#<client_id:u32>#<software:String>#<version:String>#%
-    #[command(code = "PN")]
-    PlayerCount(u8, u8),                // PN#<player_count:u8>#<max_players:u8>#%
-}
+use crate::connection_state::ConnectionState;
+use crate::networking::{ao_bool, Command, FromStrIter, WithStrIter};
+
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+};
+
+#[rustfmt::skip]
+#[derive(Debug, Command, PartialEq)]
+#[command(handler = "crate::server::AO2MessageHandler")]
+pub enum ClientCommand {
+    #[command(code = "HI", handle = "handle_handshake")]
+    Handshake(String),                           // HI#<hdid:String>#%
+    #[command(code = "ID", handle = "handle_client_version")]
+    ClientVersion(String, String),               // ID#<software:String>#<version:String>#%
+    #[command(code = "CH", handle = "handle_keepalive")]
+    KeepAlive(i32),                              // CH
+    #[command(code = "askchaa", handle = "handle_ask_list_lengths")]
+    AskListLengths,                              // askchaa
+    #[command(code = "askchar2", handle = "handle_ask_list_characters")]
+    AskListCharacters,                           // askchar
+    #[command(code = "AN", handle = "handle_character_list")]
+    CharacterList(u32),                          // AN#<page:u32>#%
+    #[command(code = "AE", handle = "handle_evidence_list")]
+    EvidenceList(u32),                           // AE#<page:u32>#%
+    #[command(code = "AM", handle = "handle_music_list")]
+    MusicList(u32),                              // AM#<page:u32>#%
+    #[command(code = "RC", handle = "handle_ao2_character_list")]
+    AO2CharacterList,                            // RC#%
+    #[command(code = "AM", handle = "handle_ao2_music_list")]
+    AO2MusicList,                                // AM#%
+    #[command(code = "RM", handle = "handle_ao2_music_list")]
+    AO2RequestMusic,                             // RM#%
+    #[command(code = "RD", handle = "handle_ao2_ready")]
+    AO2Ready,                                    // RD#%
+    #[command(code = "CC", handle = "handle_select_character")]
+    SelectCharacter(u32, i32, String),           /* CC<client_id:u32>#
+                                                  * <char_id:i32>#<hdid:
+                                                  * String>#% */
+    #[command(code = "MS", handle = "handle_ic_message")]
+    ICMessage(
+        #[command(flatten)] Box<ICMessageArgs>), // MS#<see ICMessageArgs>#%
+    #[command(code = "CT", handle = "handle_ooc_message")]
+    OOCMessage(String, String),                  /* CT#<name:String>#
+                                                  * <message:String>#% */
+    #[command(code = "MC", handle = "handle_play_song")]
+    PlaySong(String, i32, Option<String>, Option<u32>),
+                                                 /* MC#<song_or_area:String>#
+                                                  * <char_id:i32>#<showname:
+                                                  * String>?#<effects:u32>?#% */
+    #[command(code = "RT", handle = "handle_wtce_buttons")]
+    WTCEButtons(String, Option<u32>),            // RT#<type:String>#<variant:u32>?#%
+    #[command(code = "SETCASE", handle = "handle_set_case_preferences")]                 /* SETCASE#<cases:String>#<will_cm:boolean>#<will_def:boolean>#<will_pro:boolean>#<will_judge:boolean>#<will_jury:boolean>#<will_steno:boolean>#% */
+    SetCasePreferences(String, #[command(flatten)] CasePreferences),
+    #[command(code = "CASEA", handle = "handle_case_announce")]                   // CASEA
+    CaseAnnounce(String, #[command(flatten)] CasePreferences),
+    #[command(code = "HP", handle = "handle_penalties")]
+    Penalties(u32, u32),                         /* HP#<type:u32>#
+                                                  * <new_value:u32>#% */
+    #[command(code = "PE", handle = "handle_add_evidence")]
+    AddEvidence(
+        #[command(flatten)] EvidenceArgs),       /* PE#<name:String>#<description:String>#
+                                                  * <image:String>#% */
+    #[command(code = "DE", handle = "handle_delete_evidence")]
+    DeleteEvidence(u32),                         // DE#<id:u32>#%
+    #[command(code = "EE", handle = "handle_edit_evidence")]
+    EditEvidence(u32, #[command(flatten)] EvidenceArgs),
+                                                 /* EE#<id:u32>#<name:String>#
+                                                  * <description:String>#<image:
+                                                  * String>#% */
+    #[command(code = "ZZ", handle = "handle_call_mod_button")]
+    CallModButton(Option<String>),               // ZZ#<reason:String>?#%
+}
+
+impl ClientCommand {
+    /// Connection states in which clients may send the command
+    pub fn legal_states(&self) -> &'static [ConnectionState] {
+        use ConnectionState::*;
+
+        match self {
+            ClientCommand::Handshake(_) => &[Connected],
+            ClientCommand::ClientVersion(..) => &[Handshaken],
+            ClientCommand::KeepAlive(_) => ConnectionState::ALL,
+            ClientCommand::AskListLengths
+            | ClientCommand::AskListCharacters
+            | ClientCommand::CharacterList(_)
+            | ClientCommand::EvidenceList(_)
+            | ClientCommand::MusicList(_)
+            | ClientCommand::AO2CharacterList
+            | ClientCommand::AO2MusicList
+            | ClientCommand::AO2RequestMusic => &[Identified, Joined],
+            ClientCommand::AO2Ready => &[Identified],
+            ClientCommand::SelectCharacter(..)
+            | ClientCommand::ICMessage(_)
+            | ClientCommand::OOCMessage(..)
+            | ClientCommand::PlaySong(..)
+            | ClientCommand::WTCEButtons(..)
+            | ClientCommand::SetCasePreferences(..)
+            | ClientCommand::CaseAnnounce(..)
+            | ClientCommand::Penalties(..)
+            | ClientCommand::AddEvidence(_)
+            | ClientCommand::DeleteEvidence(_)
+            | ClientCommand::EditEvidence(..)
+            | ClientCommand::CallModButton(_) => &[Joined],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, WithStrIter)]
+pub struct EvidenceArgs {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+}
+
+/// Arguments of an in-character message.
+///
+/// Parsed from the client `MS` layout, which is 15 fields long for 2.6
+/// clients and grows up to 26 fields for 2.8 ones; missing trailing fields
+/// get their defaults. Serialized in the full server `MS` layout, which
+/// additionally carries the pairing partner's `other_*` fields. These are
+/// filled in by the server and never read from the client. Older clients
+/// get the layout cut down to what they know, see `written_args`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ICMessageArgs {
+    pub desk_mod: String,
+    pub preanim: String,
+    /// Character folder
+    pub folder: String,
+    pub emote: String,
+    pub text: String,
+    pub pos: String,
+    pub sfx: String,
+    pub emote_modifier: u8,
+    pub char_id: i32,
+    pub sfx_delay: i32,
+    pub shout_modifier: String,
+    pub evidence_id: u32,
+    pub flip: bool,
+    pub realization: bool,
+    pub text_color: u8,
+    pub showname: String,
+    /// Character the sender wants to pair with, -1 if none
+    pub other_charid: i32,
+    pub other_name: String,
+    pub other_emote: String,
+    pub self_offset: String,
+    pub other_offset: String,
+    pub other_flip: bool,
+    pub noninterrupting_preanim: bool,
+    pub sfx_looping: bool,
+    pub screenshake: bool,
+    pub frames_shake: String,
+    pub frames_realization: String,
+    pub frames_sfx: String,
+    pub additive: bool,
+    pub effect: String,
+    /// How many fields of the server layout get written, all of them if
+    /// `None`. Set by the server, never read from the client.
+    pub written_args: Option<usize>,
+}
+
+impl ICMessageArgs {
+    /// Number of fields sent by the oldest supported (2.6) clients
+    pub const MIN_ARGS: usize = 15;
+    /// Length of the server layout up to `noninterrupting_preanim`, which
+    /// is what 2.6 and 2.7 clients understand
+    pub const PAIRING_ARGS: usize = 23;
+}
+
+impl FromStrIter for ICMessageArgs {
+    type Error = anyhow::Error;
+
+    fn from_str_iter<I, S>(it: I) -> Result<Self, Self::Error>
+    where
+        S: AsRef<str>,
+        I: Iterator<Item = S>,
+    {
+        fn parse<T>(arg: &str) -> Result<T, anyhow::Error>
+        where
+            T: FromStr,
+            T::Err: Display,
+        {
+            arg.parse().map_err(|e| anyhow::anyhow!("{}: {:?}", e, arg))
+        }
+
+        let args: Vec<S> = it.collect();
+        if args.len() < Self::MIN_ARGS {
+            anyhow::bail!("Not enough args");
+        }
+        let arg = |i: usize| args.get(i).map(AsRef::as_ref).unwrap_or("");
+        let opt_arg = |i: usize, default: &'static str| {
+            args.get(i).map(AsRef::as_ref).unwrap_or(default)
+        };
+
+        Ok(Self {
+            desk_mod: arg(0).into(),
+            preanim: arg(1).into(),
+            folder: arg(2).into(),
+            emote: arg(3).into(),
+            text: arg(4).into(),
+            pos: arg(5).into(),
+            sfx: arg(6).into(),
+            emote_modifier: parse(arg(7))?,
+            char_id: parse(arg(8))?,
+            sfx_delay: parse(arg(9))?,
+            shout_modifier: arg(10).into(),
+            evidence_id: parse(arg(11))?,
+            flip: ao_bool::from_arg(opt_arg(12, "0"))?,
+            realization: ao_bool::from_arg(opt_arg(13, "0"))?,
+            text_color: parse(arg(14))?,
+            showname: arg(15).into(),
+            // 2.9 clients send `<char_id>^<front/back>`
+            other_charid: parse(
+                opt_arg(16, "-1").split('^').next().unwrap_or_default(),
+            )?,
+            other_name: String::new(),
+            other_emote: String::new(),
+            self_offset: opt_arg(17, "0").into(),
+            other_offset: "0".into(),
+            other_flip: false,
+            noninterrupting_preanim: ao_bool::from_arg(opt_arg(18, "0"))?,
+            sfx_looping: ao_bool::from_arg(opt_arg(19, "0"))?,
+            screenshake: ao_bool::from_arg(opt_arg(20, "0"))?,
+            frames_shake: arg(21).into(),
+            frames_realization: arg(22).into(),
+            frames_sfx: arg(23).into(),
+            additive: ao_bool::from_arg(opt_arg(24, "0"))?,
+            effect: arg(25).into(),
+            written_args: None,
+        })
+    }
+}
+
+impl IntoIterator for &ICMessageArgs {
+    type Item = String;
+    type IntoIter = <Vec<String> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let mut args = vec![
+            self.desk_mod.clone(),
+            self.preanim.clone(),
+            self.folder.clone(),
+            self.emote.clone(),
+            self.text.clone(),
+            self.pos.clone(),
+            self.sfx.clone(),
+            self.emote_modifier.to_string(),
+            self.char_id.to_string(),
+            self.sfx_delay.to_string(),
+            self.shout_modifier.clone(),
+            self.evidence_id.to_string(),
+            ao_bool::to_arg(&self.flip),
+            ao_bool::to_arg(&self.realization),
+            self.text_color.to_string(),
+            self.showname.clone(),
+            self.other_charid.to_string(),
+            self.other_name.clone(),
+            self.other_emote.clone(),
+            self.self_offset.clone(),
+            self.other_offset.clone(),
+            ao_bool::to_arg(&self.other_flip),
+            ao_bool::to_arg(&self.noninterrupting_preanim),
+            ao_bool::to_arg(&self.sfx_looping),
+            ao_bool::to_arg(&self.screenshake),
+            self.frames_shake.clone(),
+            self.frames_realization.clone(),
+            self.frames_sfx.clone(),
+            ao_bool::to_arg(&self.additive),
+            self.effect.clone(),
+        ];
+        if let Some(len) = self.written_args {
+            args.truncate(len);
+        }
+        args.into_iter()
+    }
+}
+
+/// Roles a client wants to play in cases, or roles a case needs
+#[derive(Debug, Clone, Copy, Default, PartialEq, WithStrIter)]
+pub struct CasePreferences {
+    #[command(with = "ao_bool")]
+    pub cm: bool,
+    #[command(with = "ao_bool")]
+    pub def: bool,
+    #[command(with = "ao_bool")]
+    pub pro: bool,
+    #[command(with = "ao_bool")]
+    pub judge: bool,
+    #[command(with = "ao_bool")]
+    pub jury: bool,
+    #[command(with = "ao_bool")]
+    pub steno: bool,
+}
+
+impl CasePreferences {
+    /// Names of the roles, as used in `/casing`
+    pub const ROLES: [&'static str; 6] =
+        ["cm", "def", "pro", "judge", "jury", "steno"];
+
+    /// Preferences with just the named roles
+    pub fn from_roles<S: AsRef<str>>(
+        roles: &[S],
+    ) -> Result<Self, anyhow::Error> {
+        let mut prefs = Self::default();
+        for role in roles {
+            let role = role.as_ref();
+            let flag = match role {
+                "cm" => &mut prefs.cm,
+                "def" => &mut prefs.def,
+                "pro" => &mut prefs.pro,
+                "judge" => &mut prefs.judge,
+                "jury" => &mut prefs.jury,
+                "steno" => &mut prefs.steno,
+                _ => anyhow::bail!("Unknown role: {:?}", role),
+            };
+            *flag = true;
+        }
+        Ok(prefs)
+    }
+
+    pub fn roles(&self) -> Vec<&'static str> {
+        let flags =
+            [self.cm, self.def, self.pro, self.judge, self.jury, self.steno];
+        Self::ROLES
+            .iter()
+            .zip(&flags)
+            .filter(|(_, &flag)| flag)
+            .map(|(&role, _)| role)
+            .collect()
+    }
+
+    /// Whether any of the roles is wanted by both
+    pub fn overlaps(&self, other: &Self) -> bool {
+        let other = other.roles();
+        self.roles().iter().any(|role| other.contains(role))
+    }
+}
+
+#[rustfmt::skip]
+#[derive(Debug, Clone, Command)]
+pub enum ServerCommand {
+    #[command(code = "HI")]
+    Handshake(String),                  // HI#<hdid:String>#%
+    #[command(code = "CHECK")]
+    KeepAlive,                          // CHECK#%
+    #[command(code = "decryptor")]
+    Decryptor(u32),                     // decryptor#<i:u32>#%
+    #[command(code = "BD")]
+    BanReason(String),                  // BD#<reason:String>#%,
+    #[command(code = "KK")]
+    Kicked(String),                     // KK#<reason:String>#%
+    #[command(code = "KB")]
+    Banned(String),                     // KB#<reason:String>#%
+    #[command(code = "ID")]
+    ServerVersion(u8, String, String),  // ID#<client_id:u32>#<software:String>#<version:String>#%
+    #[command(code = "PN")]
+    PlayerCount(u8, u8),                // PN#<player_count:u8>#<max_players:u8>#%
+    #[command(code = "FL")]
+    FeatureList(Vec<String>),           // FL#<feature:String>#...#%
+    #[command(code = "ASS")]
+    AssetUrl(String),                   // ASS#<url:String>#%
+    #[command(code = "SI")]
+    ListLengths(u32, u32, u32),         // SI#<characters:u32>#<evidence:u32>#<music:u32>#%
+    #[command(code = "CI")]
+    CharacterInfo(Vec<String>),         // CI#<char_id:u32>#<name:String>&<desc:String>&<...>#...#%
+    #[command(code = "SC")]
+    CharacterList(Vec<String>),         // SC#<name:String>#...#%
+    #[command(code = "CharsCheck")]
+    TakenCharacters(Vec<i32>),          // CharsCheck#<-1 if taken, 0 otherwise>#...#%
+    #[command(code = "PV")]
+    CharacterSelected(u8, String, i32), // PV#<client_id:u8>#CID#<char_id:i32>#%
+    #[command(code = "EM")]
+    MusicInfo(Vec<String>),             // EM#<id:u32>#<name:String>#...#%
+    #[command(code = "SM")]
+    AreaAndMusicList(Vec<String>),      // SM#<area:String>#...#<category:String>#<song:String>#...#%
+    #[command(code = "MC")]
+    PlaySong(
+        String,
+        i32,
+        String,
+        #[command(with = "ao_bool")] bool,
+        u32,
+        Option<u32>,
+    ),                                  /* MC#<song:String>#<char_id:i32>#<showname:String>#
+                                         * <looping:bool>#<channel:u32>#<effects:u32>?#% */
+    #[command(code = "MC")]
+    LegacyPlaySong(String, i32, String), // MC#<song:String>#<char_id:i32>#<showname:String>#%
+    #[command(code = "CT")]
+    OOCMessage(
+        String,
+        String,
+        #[command(with = "ao_bool")] bool,
+    ),                                  // CT#<name:String>#<message:String>#<from_server:bool>#%
+    #[command(code = "RT")]
+    WTCEButtons(String, Option<u32>),   // RT#<type:String>#<variant:u32>?#%
+    #[command(code = "HP")]
+    Penalties(u32, u32),                // HP#<type:u32>#<value:u32>#%
+    #[command(code = "LE")]
+    EvidenceList(Vec<String>),          // LE#<name:String>&<description:String>&<image:String>#...#%
+    #[command(code = "ZZ")]
+    ModCall(String),                    // ZZ#<message:String>#%
+    #[command(code = "CASEA")]
+    CaseAnnounce(
+        String,
+        #[command(flatten)] CasePreferences,
+    ),                                  /* CASEA#<message:String>#<cm:bool>#<def:bool>#
+                                         * <pro:bool>#<judge:bool>#<jury:bool>#<steno:bool>#% */
+    #[command(code = "BN")]
+    Background(String),                 // BN#<background:String>#%
+    #[command(code = "DONE")]
+    Done,                               // DONE#%
+    #[command(code = "MS")]
+    ICMessage(
+        #[command(flatten)] Box<ICMessageArgs>), // MS#<see ICMessageArgs>#%
+}
+
+impl ServerCommand {
+    /// Whether the arguments consist of sub-arguments separated by `&`
+    pub fn has_subargs(&self) -> bool {
+        matches!(
+            self,
+            ServerCommand::CharacterInfo(_) | ServerCommand::EvidenceList(_)
+        )
+    }
+}
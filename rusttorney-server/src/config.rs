@@ -1,4 +1,7 @@
+use crate::features::Version;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::path::Path;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -7,10 +10,72 @@ pub struct Config {
     pub multiclient_limit: u8,
     pub max_chars: u32,
     pub zalgo_tolerance: u8,
+    /// What to do with clients sending commands they may not send yet
+    #[serde(default)]
+    pub out_of_order_packets: OutOfOrderPolicy,
     pub general: GeneralConfig,
     pub masterserver: MasterServerConfig,
     pub wtce_floodguard: FloodGuardConfig,
     pub music_change_floodguard: FloodGuardConfig,
+    #[serde(default = "default_modcall_floodguard")]
+    pub modcall_floodguard: FloodGuardConfig,
+    /// `/login` attempts, counted per IPID
+    #[serde(default = "default_login_floodguard")]
+    pub login_floodguard: FloodGuardConfig,
+    /// Loaded from `areas.toml`, see [`Config::load`]
+    #[serde(skip)]
+    pub areas: Vec<AreaConfig>,
+    /// Loaded from `characters.toml`, see [`Config::load`]. Index in this
+    /// list is the character ID
+    #[serde(skip)]
+    pub characters: Vec<String>,
+    /// Loaded from `music.toml`, see [`Config::load`]
+    #[serde(skip)]
+    pub music: Vec<MusicCategory>,
+}
+
+impl Config {
+    /// Reads `config.toml` and the lists living next to it (`areas.toml`,
+    /// `characters.toml`, `music.toml`) from the config directory.
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let mut config: Config = read_toml(dir.join("config.toml"))?;
+        let areas: AreasConfig = read_toml(dir.join("areas.toml"))?;
+
+        if areas.area.is_empty() {
+            anyhow::bail!("areas.toml must contain at least one area");
+        }
+        config.areas = areas.area;
+
+        let characters: CharactersConfig =
+            read_toml(dir.join("characters.toml"))?;
+        if characters.characters.is_empty() {
+            anyhow::bail!(
+                "characters.toml must contain at least one character"
+            );
+        }
+        config.characters = characters.characters;
+
+        let music: MusicConfig = read_toml(dir.join("music.toml"))?;
+        config.music = music.category;
+
+        Ok(config)
+    }
+
+    pub fn song(&self, name: &str) -> Option<&SongConfig> {
+        self.music
+            .iter()
+            .flat_map(|category| &category.songs)
+            .find(|song| song.name == name)
+    }
+}
+
+fn read_toml<T: DeserializeOwned>(path: impl AsRef<Path>) -> anyhow::Result<T> {
+    let path = path.as_ref();
+    let string = std::fs::read_to_string(path).map_err(|e| {
+        anyhow::anyhow!("Failed to read {}: {}", path.display(), e)
+    })?;
+    Ok(toml::from_str(&string)?)
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,7 +87,15 @@ pub struct GeneralConfig {
     pub local: bool,
     pub modpass: String,
     pub motd: String,
+    /// Whether to accept WebAO clients on `websocket_port`
+    #[serde(default)]
+    pub use_websockets: bool,
     pub websocket_port: Option<u32>,
+    /// Where clients download assets missing on their side from
+    pub asset_url: Option<String>,
+    /// Clients older than this or with an unknown version are refused.
+    /// WebAO, which sends no version, is let in.
+    pub min_client_version: Option<Version>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,6 +106,47 @@ pub struct MasterServerConfig {
     pub port: u16,
     pub name: String,
     pub description: String,
+    #[serde(default)]
+    pub mode: AdvertiserMode,
+    /// Where server info is POSTed in the `http` mode
+    #[serde(default = "default_advertise_url")]
+    pub advertise_url: String,
+    /// Seconds between POSTs in the `http` mode
+    #[serde(default = "default_advertise_interval")]
+    pub advertise_interval: u64,
+    /// IP clients should connect to. Server lists use the IP the info comes
+    /// from if it's missing.
+    pub public_ip: Option<String>,
+}
+
+/// See [`Config::out_of_order_packets`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfOrderPolicy {
+    /// Log and skip the command
+    #[default]
+    Ignore,
+    /// Log and disconnect the client
+    Disconnect,
+}
+
+/// How the server gets listed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdvertiserMode {
+    /// `SCC` over TCP to the legacy master server at `ip:port`
+    #[default]
+    Legacy,
+    /// JSON POSTed to `advertise_url`
+    Http,
+}
+
+fn default_advertise_url() -> String {
+    "https://servers.aceattorneyonline.com/servers".into()
+}
+
+fn default_advertise_interval() -> u64 {
+    5 * 60
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,11 +156,184 @@ pub struct FloodGuardConfig {
     pub mute_length: u32,
 }
 
+/// Five `/login` attempts a minute, then five minutes of waiting
+fn default_login_floodguard() -> FloodGuardConfig {
+    FloodGuardConfig {
+        times_per_interval: 5,
+        interval_length: 60,
+        mute_length: 300,
+    }
+}
+
+/// One modcall a minute
+fn default_modcall_floodguard() -> FloodGuardConfig {
+    FloodGuardConfig {
+        times_per_interval: 1,
+        interval_length: 60,
+        mute_length: 60,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AreasConfig {
+    area: Vec<AreaConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CharactersConfig {
+    characters: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MusicConfig {
+    #[serde(default)]
+    category: Vec<MusicCategory>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MusicCategory {
+    pub name: String,
+    pub songs: Vec<SongConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SongConfig {
+    /// File name, as clients look it up
+    pub name: String,
+    /// Length in seconds, if known
+    pub length: Option<u32>,
+    /// Background music loops, jingles should be played once
+    #[serde(default = "default_looping")]
+    pub looping: bool,
+}
+
+fn default_looping() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AreaConfig {
+    pub name: String,
+    pub background: String,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub spectatable: bool,
+    #[serde(default)]
+    pub evidence_mode: EvidenceMode,
+    pub max_players: Option<u8>,
+}
+
+/// Who is allowed to modify the evidence list of an area
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvidenceMode {
+    #[default]
+    Anyone,
+    CaseManagers,
+    Mods,
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    const CONFIG_STR: &str = r#"
+    debug = false
+    timeout = 250
+    multiclient_limit = 16
+    max_chars = 256
+    zalgo_tolerance = 3
+
+
+    [general]
+    hostname = "<dollar>H"
+    host = "0.0.0.0"
+    playerlimit = 100
+    port = 27016
+    local = false
+    modpass = "mod"
+    motd = "Welcome to my server!"
+    use_websockets = true
+    websocket_port = 50001
+
+    [masterserver]
+    use = true
+    ip = "master.aceattorneyonline.com"
+    port = 27016
+    name = "My server"
+    description = "My server description!"
+
+    [music_change_floodguard]
+    times_per_interval = 3
+    interval_length = 20
+    mute_length = 180
+
+    [wtce_floodguard]
+    times_per_interval = 5
+    interval_length = 10
+    mute_length = 1000
+
+    [modcall_floodguard]
+    times_per_interval = 1
+    interval_length = 60
+    mute_length = 60
+    "#;
+
+    const AREAS_STR: &str = r#"
+    [[area]]
+    name = "Basement"
+    background = "gs4"
+
+    [[area]]
+    name = "Courtroom 1"
+    background = "default"
+    evidence_mode = "case_managers"
+    "#;
+
+    const CHARACTERS_STR: &str = r#"
+    characters = ["Phoenix", "Edgeworth", "Maya"]
+    "#;
+
+    const MUSIC_STR: &str = r#"
+    [[category]]
+    name = "==Trial=="
+    songs = [
+        { name = "Objection.opus", length = 95 },
+        { name = "Gavel.opus", length = 2, looping = false },
+    ]
+
+    [[category]]
+    name = "==Other=="
+    songs = [{ name = "Lobby.opus" }]
+    "#;
+
+    /// Sample config with a couple of areas, characters and songs, for use
+    /// in tests
+    pub(crate) fn test_config() -> Config {
+        let mut config: Config = toml::from_str(CONFIG_STR).unwrap();
+        let areas: AreasConfig = toml::from_str(AREAS_STR).unwrap();
+        let characters: CharactersConfig =
+            toml::from_str(CHARACTERS_STR).unwrap();
+        let music: MusicConfig = toml::from_str(MUSIC_STR).unwrap();
+        config.areas = areas.area;
+        config.characters = characters.characters;
+        config.music = music.category;
+        config
+    }
+
+    #[test]
+    fn modcall_floodguard_is_optional() {
+        let (without, _) = CONFIG_STR
+            .split_at(CONFIG_STR.find("[modcall_floodguard]").unwrap());
+        let config: Config = toml::from_str(without).unwrap();
+
+        assert_eq!(config.modcall_floodguard.times_per_interval, 1);
+        assert_eq!(config.modcall_floodguard.interval_length, 60);
+    }
+
     #[test]
+    #[allow(clippy::needless_borrow, clippy::bool_assert_comparison)]
     fn test_config_parsing() {
         let config_str = r#"
         debug = false
@@ -89,4 +376,46 @@ mod tests {
         assert_eq!(config.debug, false);
         assert_eq!(config.masterserver.name, "My server")
     }
+
+    #[test]
+    fn default_advertiser_mode() {
+        let config = test_config();
+
+        assert_eq!(config.masterserver.mode, AdvertiserMode::Legacy)
+    }
+
+    #[test]
+    fn test_areas_parsing() {
+        let areas_str = r#"
+        [[area]]
+        name = "Basement"
+        background = "gs4"
+
+        [[area]]
+        name = "Courtroom 1"
+        background = "default"
+        locked = true
+        evidence_mode = "case_managers"
+        max_players = 10
+        "#;
+        let areas: AreasConfig = toml::from_str(areas_str).unwrap();
+
+        assert_eq!(areas.area.len(), 2);
+        assert_eq!(areas.area[0].evidence_mode, EvidenceMode::Anyone);
+        assert!(!areas.area[0].locked);
+        assert_eq!(areas.area[1].evidence_mode, EvidenceMode::CaseManagers);
+        assert_eq!(areas.area[1].max_players, Some(10));
+    }
+
+    #[test]
+    fn test_music_parsing() {
+        let config = test_config();
+
+        assert_eq!(config.music.len(), 2);
+        assert_eq!(config.music[0].songs[0].length, Some(95));
+        assert!(config.music[0].songs[0].looping);
+        assert!(!config.song("Gavel.opus").unwrap().looping);
+        assert_eq!(config.song("Lobby.opus").unwrap().length, None);
+        assert!(config.song("==Other==").is_none());
+    }
 }
@@ -0,0 +1,77 @@
+use crate::command::ClientCommand;
+
+/// How far a client got in joining the server. Clients go through the
+/// states in order, see [`ClientCommand::legal_states`] for what they may
+/// send in each of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionState {
+    /// Waiting for `HI`
+    Connected,
+    /// Waiting for `ID`
+    Handshaken,
+    /// Loading the lists, until `RD`
+    Identified,
+    /// In the courtroom
+    Joined,
+}
+
+impl ConnectionState {
+    pub const ALL: &'static [ConnectionState] = &[
+        ConnectionState::Connected,
+        ConnectionState::Handshaken,
+        ConnectionState::Identified,
+        ConnectionState::Joined,
+    ];
+
+    /// State after the command got handled
+    pub fn after(self, command: &ClientCommand) -> Self {
+        match (self, command) {
+            (ConnectionState::Connected, ClientCommand::Handshake(_)) => {
+                ConnectionState::Handshaken
+            }
+            (ConnectionState::Handshaken, ClientCommand::ClientVersion(..)) => {
+                ConnectionState::Identified
+            }
+            (ConnectionState::Identified, ClientCommand::AO2Ready) => {
+                ConnectionState::Joined
+            }
+            (state, _) => state,
+        }
+    }
+
+    pub fn allows(self, command: &ClientCommand) -> bool {
+        command.legal_states().contains(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_in_order() {
+        let version =
+            ClientCommand::ClientVersion("AO2".into(), "2.8.5".into());
+        let select = ClientCommand::SelectCharacter(0, 1, "hdid".into());
+        let mut state = ConnectionState::Connected;
+        assert!(!state.allows(&version));
+        assert!(!state.allows(&select));
+
+        for command in &[
+            ClientCommand::Handshake("hdid".into()),
+            version,
+            ClientCommand::AO2CharacterList,
+        ] {
+            assert!(state.allows(command));
+            state = state.after(command);
+        }
+        assert_eq!(state, ConnectionState::Identified);
+        assert!(!state.allows(&select));
+
+        state = state.after(&ClientCommand::AO2Ready);
+        assert_eq!(state, ConnectionState::Joined);
+        assert!(state.allows(&select));
+        assert!(!state.allows(&ClientCommand::Handshake("hdid".into())));
+        assert!(state.allows(&ClientCommand::KeepAlive(0)));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::command::{ICMessageArgs, ServerCommand};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Features this server supports, announced to clients in `FL`
+pub const SERVER_FEATURES: &[&str] = &[
+    "noencryption",
+    "fastloading",
+    "yellowtext",
+    "flipping",
+    "customobjections",
+    "deskmod",
+    "evidence",
+    "cccc_ic_support",
+    "casing_alerts",
+    "modcall_reason",
+    "looping_sfx",
+    "additive",
+    "effects",
+];
+
+/// Client version sent in `ID`, e.g. `2.8.5`
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize,
+)]
+#[serde(try_from = "String")]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self { major, minor, patch }
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    /// Parses `major.minor[.patch]`, ignoring suffixes like `-rc1`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid version: {:?}", s);
+        let mut parts = s.splitn(3, '.').map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(part, |end| &part[..end]);
+            digits.parse::<u32>().map_err(|_| invalid())
+        });
+
+        let major = parts.next().ok_or_else(invalid)??;
+        let minor = parts.next().ok_or_else(invalid)??;
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// What a client sent in `ID`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientVersion {
+    /// WebAO sends its name instead of a version and keeps up with the
+    /// latest protocol
+    WebAO,
+    Known(Version),
+    /// Version which couldn't be parsed
+    Unknown,
+}
+
+impl ClientVersion {
+    pub fn new(software: &str, version: &str) -> Self {
+        if software.eq_ignore_ascii_case("webAO") {
+            return ClientVersion::WebAO;
+        }
+        version.parse().map_or(ClientVersion::Unknown, ClientVersion::Known)
+    }
+
+    pub fn version(self) -> Option<Version> {
+        match self {
+            ClientVersion::Known(version) => Some(version),
+            _ => None,
+        }
+    }
+
+    pub fn features(self) -> FeatureSet {
+        match self {
+            ClientVersion::WebAO => FeatureSet::LATEST,
+            _ => FeatureSet::of(self.version()),
+        }
+    }
+
+    /// Whether the client has to be refused when `min` is required.
+    /// Clients with unknown versions can't prove they are new enough.
+    pub fn is_older_than(self, min: Version) -> bool {
+        match self {
+            ClientVersion::WebAO => false,
+            ClientVersion::Known(version) => version < min,
+            ClientVersion::Unknown => true,
+        }
+    }
+}
+
+/// What a client understands, depending on its version. Clients which
+/// haven't sent `ID` yet or sent an unknown version get the oldest packet
+/// shapes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureSet {
+    /// `CASEA` notifications, since 2.6
+    pub casing_alerts: bool,
+    /// `looping` and `channel` in `MC`, since 2.8
+    pub looping_music: bool,
+    /// Looping sfx, screenshake, frame effects and additive text in `MS`,
+    /// since 2.8
+    pub ic_effects: bool,
+    /// `effects` in `MC`, since 2.9
+    pub music_effects: bool,
+}
+
+impl FeatureSet {
+    /// Everything the server supports
+    pub const LATEST: Self = Self {
+        casing_alerts: true,
+        looping_music: true,
+        ic_effects: true,
+        music_effects: true,
+    };
+
+    pub fn of(version: Option<Version>) -> Self {
+        let version = version.unwrap_or_default();
+        Self {
+            casing_alerts: version >= Version::new(2, 6, 0),
+            looping_music: version >= Version::new(2, 8, 0),
+            ic_effects: version >= Version::new(2, 8, 0),
+            music_effects: version >= Version::new(2, 9, 0),
+        }
+    }
+}
+
+impl ServerCommand {
+    /// Changes the command to the shape the client understands
+    pub fn adapt_to(self, features: &FeatureSet) -> Self {
+        match self {
+            ServerCommand::PlaySong(name, char_id, showname, ..)
+                if !features.looping_music =>
+            {
+                ServerCommand::LegacyPlaySong(name, char_id, showname)
+            }
+            ServerCommand::PlaySong(
+                name,
+                char_id,
+                showname,
+                looping,
+                channel,
+                effects,
+            ) => ServerCommand::PlaySong(
+                name,
+                char_id,
+                showname,
+                looping,
+                channel,
+                effects.filter(|_| features.music_effects),
+            ),
+            ServerCommand::ICMessage(mut args) => {
+                args.written_args = Some(ICMessageArgs::PAIRING_ARGS)
+                    .filter(|_| !features.ic_effects);
+                ServerCommand::ICMessage(args)
+            }
+            command => command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::{Command, FromStrIter};
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!("2.8.5".parse::<Version>().unwrap(), Version::new(2, 8, 5));
+        assert_eq!("2.9".parse::<Version>().unwrap(), Version::new(2, 9, 0));
+        assert_eq!(
+            "2.10.1-rc2".parse::<Version>().unwrap(),
+            Version::new(2, 10, 1)
+        );
+        assert!("webAO".parse::<Version>().is_err());
+        assert!("2".parse::<Version>().is_err());
+        assert!(Version::new(2, 10, 0) > Version::new(2, 9, 9));
+    }
+
+    #[test]
+    fn adapt_music_to_client_versions() {
+        let play_song = || {
+            ServerCommand::PlaySong(
+                "Objection.opus".into(),
+                2,
+                "Nick".into(),
+                true,
+                0,
+                Some(1),
+            )
+        };
+        let shape = |version: &str| {
+            let features = FeatureSet::of(version.parse().ok());
+            play_song().adapt_to(&features).to_message()
+        };
+
+        assert_eq!(shape("1.8"), "MC#Objection.opus#2#Nick#%");
+        assert_eq!(shape("2.6.2"), "MC#Objection.opus#2#Nick#%");
+        assert_eq!(shape("2.8.5"), "MC#Objection.opus#2#Nick#1#0#%");
+        assert_eq!(shape("2.9.0"), "MC#Objection.opus#2#Nick#1#0#1#%");
+        assert!(FeatureSet::of(None) == FeatureSet::default());
+    }
+
+    #[test]
+    fn refuse_outdated_clients() {
+        let min = Version::new(2, 8, 0);
+        let webao = ClientVersion::new("webAO", "webAO");
+        let garbage = ClientVersion::new("AO2", "banana");
+
+        assert_eq!(webao.features(), FeatureSet::LATEST);
+        assert!(!webao.is_older_than(min));
+        assert_eq!(garbage.features(), FeatureSet::default());
+        assert!(garbage.is_older_than(min));
+        assert!(ClientVersion::new("AO2", "2.6.2").is_older_than(min));
+        assert!(!ClientVersion::new("AO2", "2.9.1").is_older_than(min));
+    }
+
+    #[test]
+    fn adapt_ic_messages_to_client_versions() {
+        let args = (0..26).map(|i| match i {
+            7 | 8 | 9 | 11 | 14 => "1".to_string(),
+            12 | 13 | 18 | 19 | 20 | 24 => "0".to_string(),
+            16 => "-1".to_string(),
+            i => format!("arg{}", i),
+        });
+        let message = ServerCommand::ICMessage(Box::new(
+            ICMessageArgs::from_str_iter(args).unwrap(),
+        ));
+        let arg_count = |version: &str| {
+            let features = ClientVersion::new("AO2", version).features();
+            message.clone().adapt_to(&features).extract_args().len()
+        };
+
+        assert_eq!(arg_count("2.6.2"), 23);
+        assert_eq!(arg_count("2.7.2"), 23);
+        assert_eq!(arg_count("2.8.5"), 30);
+        assert_eq!(ClientVersion::WebAO.features(), FeatureSet::LATEST);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::FloodGuardConfig;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Sliding window rate limiter. Allows an action at most
+/// `times_per_interval` times in any `interval_length` seconds, and mutes
+/// for `mute_length` seconds those who try more often.
+///
+/// `times_per_interval = 0` disables the guard, which is also what the
+/// default guard does.
+#[derive(Debug, Clone, Default)]
+pub struct FloodGuard {
+    times_per_interval: usize,
+    interval: Duration,
+    mute_length: Duration,
+    /// When the action happened within the last interval, oldest first
+    history: VecDeque<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(config: &FloodGuardConfig) -> Self {
+        Self {
+            times_per_interval: config.times_per_interval as usize,
+            interval: Duration::from_secs(config.interval_length as u64),
+            mute_length: Duration::from_secs(config.mute_length as u64),
+            history: VecDeque::new(),
+            muted_until: None,
+        }
+    }
+
+    /// Records an attempt of the action. Fails with the time left until
+    /// unmute if the action isn't allowed.
+    pub fn check(&mut self) -> Result<(), Duration> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.times_per_interval == 0 {
+            return Ok(());
+        }
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Err(until - now);
+            }
+            self.muted_until = None;
+        }
+
+        while let Some(&oldest) = self.history.front() {
+            if now.duration_since(oldest) < self.interval {
+                break;
+            }
+            self.history.pop_front();
+        }
+
+        if self.history.len() >= self.times_per_interval {
+            self.history.clear();
+            self.muted_until = Some(now + self.mute_length);
+            return Err(self.mute_length);
+        }
+        self.history.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> FloodGuard {
+        FloodGuard::new(&FloodGuardConfig {
+            times_per_interval: 2,
+            interval_length: 10,
+            mute_length: 60,
+        })
+    }
+
+    #[test]
+    fn mute_when_flooding() {
+        let mut guard = guard();
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+
+        assert!(guard.check_at(secs(0)).is_ok());
+        assert!(guard.check_at(secs(5)).is_ok());
+        assert_eq!(guard.check_at(secs(6)), Err(Duration::from_secs(60)));
+        assert_eq!(guard.check_at(secs(36)), Err(Duration::from_secs(30)));
+        assert!(guard.check_at(secs(66)).is_ok());
+    }
+
+    #[test]
+    fn window_slides() {
+        let mut guard = guard();
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+
+        assert!(guard.check_at(secs(0)).is_ok());
+        assert!(guard.check_at(secs(9)).is_ok());
+        assert!(guard.check_at(secs(10)).is_ok());
+        assert!(guard.check_at(secs(19)).is_ok());
+        assert!(guard.check_at(secs(19)).is_err());
+    }
+
+    #[test]
+    fn disabled_guard() {
+        let mut guard = FloodGuard::default();
+        let now = Instant::now();
+
+        assert!((0..100).all(|_| guard.check_at(now).is_ok()));
+    }
+}
@@ -1,9 +1,21 @@
 use crate::{
-    command::{CasePreferences, EvidenceArgs, ServerCommand},
-    server::AO2MessageHandler,
+    area_manager::{Area, PlayingSong},
+    client_manager::Client,
+    command::{CasePreferences, EvidenceArgs, ICMessageArgs, ServerCommand},
+    features::{ClientVersion, SERVER_FEATURES},
+    networking::{
+        codec::{escape, unescape},
+        database::{MiscEvent, RoomEvent, RoomEventSource},
+    },
+    server::{AO2MessageHandler, SOFTWARE, VERSION},
+    text_filter::TextFilter,
 };
 
 use futures::SinkExt;
+use std::{convert::TryFrom, iter};
+
+/// Legacy clients load lists by pages of this length
+const PAGE_LEN: usize = 10;
 
 impl AO2MessageHandler {
     pub async fn handle_handshake(
@@ -13,31 +25,58 @@ impl AO2MessageHandler {
         self.client.hdid = hdid.clone();
         self.client_manager.lock().await.update_client(self.client.clone());
 
+        if let Some(ban) = self.db.hdid_ban(&hdid).await? {
+            self.send(ServerCommand::BanReason(ban.message()))?;
+            anyhow::bail!("HDID {} is banned (ban {})", hdid, ban.id);
+        }
         self.db.add_hdid(hdid, self.client.ipid).await?;
 
-        self.socket
-            .send(ServerCommand::ServerVersion(
-                self.client.id,
-                self.software.clone(),
-                self.version.clone(),
-            ))
-            .await?;
-
-        self.socket
-            .send(ServerCommand::PlayerCount(
-                self.player_count().await,
-                self.config.general.playerlimit,
-            ))
-            .await
+        self.send(ServerCommand::ServerVersion(
+            self.client.id,
+            SOFTWARE.into(),
+            VERSION.into(),
+        ))
     }
 
+    /// Client tells what it is. Features the client gets depend on that.
     pub async fn handle_client_version(
         &mut self,
-        _: u32,
-        _: String,
-        _: String,
+        software: String,
+        version: String,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let client_version = ClientVersion::new(&software, &version);
+        self.client.software = software;
+        self.client.version = client_version.version();
+        self.client.features = client_version.features();
+        self.client_manager.lock().await.update_client(self.client.clone());
+
+        if let Some(min) = self.config.general.min_client_version {
+            if client_version.is_older_than(min) {
+                self.send(ServerCommand::Kicked(format!(
+                    "Your client is outdated, version {} or newer is required.",
+                    min
+                )))?;
+                anyhow::bail!(
+                    "Client {} runs {} {:?}, which is older than {}",
+                    self.client.id,
+                    self.client.software,
+                    version,
+                    min
+                );
+            }
+        }
+
+        self.send(ServerCommand::PlayerCount(
+            self.player_count().await,
+            self.config.general.playerlimit,
+        ))?;
+        self.send(ServerCommand::FeatureList(
+            SERVER_FEATURES.iter().map(|&f| f.to_owned()).collect(),
+        ))?;
+        if let Some(url) = &self.config.general.asset_url {
+            self.send(ServerCommand::AssetUrl(url.clone()))?;
+        }
+        Ok(())
     }
 
     pub async fn handle_keepalive(
@@ -45,142 +84,768 @@ impl AO2MessageHandler {
         _: i32,
     ) -> Result<(), anyhow::Error> {
         self.ch_tx.send(()).await?;
-        self.socket.send(ServerCommand::KeepAlive).await
+        self.send(ServerCommand::KeepAlive)
     }
 
     pub async fn handle_ask_list_lengths(
         &mut self,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        self.send(ServerCommand::ListLengths(
+            self.config.characters.len() as u32,
+            0,
+            self.music_list().len() as u32,
+        ))
     }
 
     pub async fn handle_ask_list_characters(
         &mut self,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        self.handle_character_list(0).await
     }
 
     pub async fn handle_character_list(
         &mut self,
-        _: u32,
+        page: u32,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let args = self
+            .config
+            .characters
+            .iter()
+            .enumerate()
+            .skip(page as usize * PAGE_LEN)
+            .take(PAGE_LEN)
+            .flat_map(|(id, name)| {
+                vec![id.to_string(), format!("{}&&0&&&0&", escape(name))]
+            })
+            .collect();
+        self.send(ServerCommand::CharacterInfo(args))
     }
 
     pub async fn handle_evidence_list(
         &mut self,
-        _: u32,
+        page: u32,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+        let evidence = area.evidence_page(page as usize, PAGE_LEN);
+        drop(client_manager);
+        self.send(evidence)
     }
 
     pub async fn handle_music_list(
         &mut self,
-        _: u32,
+        page: u32,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let args = self
+            .music_list()
+            .into_iter()
+            .enumerate()
+            .skip(page as usize * PAGE_LEN)
+            .take(PAGE_LEN)
+            .flat_map(|(id, name)| vec![id.to_string(), name])
+            .collect();
+        self.send(ServerCommand::MusicInfo(args))
     }
 
     pub async fn handle_ao2_character_list(
         &mut self,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        self.send(ServerCommand::CharacterList(self.config.characters.clone()))
     }
 
     pub async fn handle_ao2_music_list(&mut self) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        self.send(ServerCommand::AreaAndMusicList(self.music_list()))
     }
 
+    /// Areas followed by the music categories with their songs. Clients
+    /// show them in a single list.
+    fn music_list(&self) -> Vec<String> {
+        let areas = self.config.areas.iter().map(|area| area.name.clone());
+        let music = self.config.music.iter().flat_map(|category| {
+            iter::once(category.name.clone())
+                .chain(category.songs.iter().map(|song| song.name.clone()))
+        });
+        areas.chain(music).collect()
+    }
+
+    /// Client has loaded the lists and is going to pick a character
     pub async fn handle_ao2_ready(&mut self) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        self.send(client_manager.chars_check(self.client.area))?;
+        self.send(area.evidence_list())?;
+        for penalty in area.penalties() {
+            self.send(penalty)?;
+        }
+        self.send(ServerCommand::Background(area.background.clone()))?;
+        self.send(ServerCommand::Done)
     }
 
     pub async fn handle_select_character(
         &mut self,
         _: u32,
-        _: u32,
+        char_id: i32,
         _: String,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let mut client_manager = self.client_manager.lock().await;
+
+        if let Err(e) =
+            client_manager.change_character(&mut self.client, char_id)
+        {
+            log::debug!(
+                "Client {} can't select character {}: {}",
+                self.client.id,
+                char_id,
+                e
+            );
+            // The client might have missed that the character got taken
+            return self.send(client_manager.chars_check(self.client.area));
+        }
+
+        self.send(ServerCommand::CharacterSelected(
+            self.client.id,
+            "CID".into(),
+            char_id,
+        ))
+    }
+
+    pub async fn handle_ic_message(
+        &mut self,
+        mut args: Box<ICMessageArgs>,
+    ) -> Result<(), anyhow::Error> {
+        let filter = TextFilter::new(&self.config);
+        let text = match filter.reject_long(&args.text) {
+            Ok(text) => text,
+            Err(violation) => {
+                return self.report_violations("message", &[violation])
+            }
+        };
+        let showname = filter.truncate(&args.showname);
+        self.report_violations("message", &text.violations)?;
+        self.report_violations("showname", &showname.violations)?;
+        args.text = text.text;
+        args.showname = showname.text;
+
+        let mut client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        if let Err(e) = self.validate_ic_message(&args, area) {
+            log::debug!(
+                "Rejected IC message from client {}: {}",
+                self.client.id,
+                e
+            );
+            return Ok(());
+        }
+
+        // Pair only if the partner wants to pair with us as well
+        let own_char_id = self.client.char_id;
+        let partner = client_manager
+            .clients_in_area(self.client.area)
+            .filter(|c| c.id != self.client.id)
+            .filter_map(|c| c.last_ic.as_ref())
+            .find(|last| {
+                last.char_id == args.other_charid
+                    && last.other_charid == own_char_id
+            });
+        if let Some(partner) = partner {
+            args.other_name = partner.folder.clone();
+            args.other_emote = partner.emote.clone();
+            args.other_offset = partner.self_offset.clone();
+            args.other_flip = partner.flip;
+        } else {
+            args.other_charid = -1;
+        }
+
+        self.client.last_ic = Some((*args).clone());
+        client_manager.update_client(self.client.clone());
+        client_manager
+            .broadcast_area(self.client.area, ServerCommand::ICMessage(args));
+        Ok(())
     }
 
-    pub async fn handle_ic_message(&mut self) -> Result<(), anyhow::Error> {
-        unimplemented!()
+    fn validate_ic_message(
+        &self,
+        args: &ICMessageArgs,
+        area: &Area,
+    ) -> Result<(), anyhow::Error> {
+        if self.client.char_id == -1 {
+            anyhow::bail!("spectators can't talk in IC");
+        }
+        if args.char_id != self.client.char_id {
+            anyhow::bail!(
+                "char_id {} doesn't match selected character {}",
+                args.char_id,
+                self.client.char_id
+            );
+        }
+        if area.locked && area.spectatable {
+            anyhow::bail!("area {} is spectatable only", area.name);
+        }
+        if !matches!(args.emote_modifier, 0 | 1 | 2 | 4 | 5 | 6) {
+            anyhow::bail!("invalid emote modifier {}", args.emote_modifier);
+        }
+        if args.text_color > 11 {
+            anyhow::bail!("invalid text color {}", args.text_color);
+        }
+        Ok(())
     }
 
     pub async fn handle_ooc_message(
         &mut self,
-        _: String,
-        _: String,
+        name: String,
+        message: String,
+    ) -> Result<(), anyhow::Error> {
+        let filter = TextFilter::new(&self.config);
+        let message = match filter.reject_long(&message) {
+            Ok(message) => message,
+            Err(violation) => {
+                return self.report_violations("message", &[violation])
+            }
+        };
+        let name = filter.truncate(&name);
+        if let Err(e) = self.validate_ooc_message(&name.text, &message.text) {
+            return self.send_server_message(e.to_string());
+        }
+        self.report_violations("name", &name.violations)?;
+        self.report_violations("message", &message.violations)?;
+        let (name, message) = (name.text, message.text);
+
+        if let Some(command) = message.strip_prefix('/') {
+            return self.handle_ooc_command(command).await;
+        }
+
+        let mut client_manager = self.client_manager.lock().await;
+        self.client.name = name.clone();
+        client_manager.update_client(self.client.clone());
+        client_manager.broadcast_area(
+            self.client.area,
+            ServerCommand::OOCMessage(name, message, false),
+        );
+        Ok(())
+    }
+
+    fn validate_ooc_message(
+        &self,
+        name: &str,
+        message: &str,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        const MAX_NAME_LEN: usize = 30;
+
+        let name = name.trim();
+        if name.is_empty() {
+            anyhow::bail!("You must enter a name to talk in OOC.");
+        }
+        if name.chars().count() > MAX_NAME_LEN {
+            anyhow::bail!(
+                "Your name is too long, the limit is {} characters.",
+                MAX_NAME_LEN
+            );
+        }
+        if is_server_name(name, &self.config.general.hostname) {
+            anyhow::bail!("That name is reserved for the server.");
+        }
+        if message.trim().is_empty() {
+            anyhow::bail!("You can't send an empty message.");
+        }
+        Ok(())
     }
 
     pub async fn handle_play_song(
         &mut self,
-        _: u32,
-        _: u32,
+        name: String,
+        char_id: i32,
+        showname: Option<String>,
+        effects: Option<u32>,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let mut client_manager = self.client_manager.lock().await;
+
+        // Areas are listed along with the music, picking one means moving
+        if let Some(area) = client_manager.areas.by_name(&name) {
+            let area_id = area.id;
+            drop(client_manager);
+            return self.change_area(area_id).await;
+        }
+
+        if let Err(e) = self.validate_song(&name, char_id) {
+            log::debug!(
+                "Rejected song {:?} from client {}: {}",
+                name,
+                self.client.id,
+                e
+            );
+            return Ok(());
+        }
+
+        let checked = self.client.music_floodguard.check();
+        client_manager.update_client(self.client.clone());
+
+        if let Err(left) = checked {
+            return self.send_server_message(format!(
+                "You changed the music too often. You can do it again in {} seconds.",
+                left.as_secs()
+            ));
+        }
+
+        let song = self.config.song(&name);
+        let looping = song.is_some_and(|song| song.looping);
+        let showname = match showname.filter(|name| !name.is_empty()) {
+            Some(showname) => {
+                let showname =
+                    TextFilter::new(&self.config).truncate(&showname);
+                self.report_violations("showname", &showname.violations)?;
+                showname.text
+            }
+            None => client_manager
+                .character_name(char_id)
+                .unwrap_or_default()
+                .to_owned(),
+        };
+
+        client_manager
+            .areas
+            .get_mut(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?
+            .song = song.map(PlayingSong::new);
+        client_manager.broadcast_area(
+            self.client.area,
+            ServerCommand::PlaySong(
+                name,
+                char_id,
+                showname,
+                looping,
+                0,
+                Some(effects.unwrap_or(0)),
+            ),
+        );
+        Ok(())
+    }
+
+    fn validate_song(
+        &self,
+        name: &str,
+        char_id: i32,
+    ) -> Result<(), anyhow::Error> {
+        if self.client.char_id == -1 {
+            anyhow::bail!("spectators can't change the music");
+        }
+        if char_id != self.client.char_id {
+            anyhow::bail!(
+                "char_id {} doesn't match selected character {}",
+                char_id,
+                self.client.char_id
+            );
+        }
+        if name != PlayingSong::STOP && self.config.song(name).is_none() {
+            anyhow::bail!("no such song");
+        }
+        Ok(())
+    }
+
+    /// Moves the client to another area and shows it what is going on there
+    async fn change_area(
+        &mut self,
+        area_id: usize,
+    ) -> Result<(), anyhow::Error> {
+        let mut client_manager = self.client_manager.lock().await;
+        let char_id = self.client.char_id;
+
+        if let Err(e) = client_manager.move_client(&mut self.client, area_id) {
+            log::debug!(
+                "Client {} can't move to area {}: {}",
+                self.client.id,
+                area_id,
+                e
+            );
+            return Ok(());
+        }
+
+        // The character was taken in the new area
+        if self.client.char_id != char_id {
+            self.send(ServerCommand::CharacterSelected(
+                self.client.id,
+                "CID".into(),
+                self.client.char_id,
+            ))?;
+        }
+
+        let area = client_manager
+            .areas
+            .get(area_id)
+            .ok_or_else(|| anyhow::anyhow!("No such area: {}", area_id))?;
+        self.send(ServerCommand::Background(area.background.clone()))?;
+        self.send(area.evidence_list())?;
+        for penalty in area.penalties() {
+            self.send(penalty)?;
+        }
+        if let Some(song) = area.current_song() {
+            self.send(ServerCommand::PlaySong(
+                song.name.clone(),
+                -1,
+                String::new(),
+                song.looping,
+                0,
+                Some(0),
+            ))?;
+        }
+        Ok(())
     }
 
     pub async fn handle_wtce_buttons(
         &mut self,
-        _: String,
+        kind: String,
+        variant: Option<u32>,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        if let Err(e) = validate_wtce(&kind, variant) {
+            log::debug!(
+                "Rejected WT/CE {:?} from client {}: {}",
+                kind,
+                self.client.id,
+                e
+            );
+            return Ok(());
+        }
+
+        let mut client_manager = self.client_manager.lock().await;
+        let checked = self.client.wtce_floodguard.check();
+        client_manager.update_client(self.client.clone());
+
+        if let Err(left) = checked {
+            return self.send_server_message(format!(
+                "You used WT/CE buttons too often. You can do it again in {} seconds.",
+                left.as_secs()
+            ));
+        }
+        client_manager.broadcast_area(
+            self.client.area,
+            ServerCommand::WTCEButtons(kind, variant),
+        );
+        Ok(())
     }
 
     pub async fn handle_set_case_preferences(
         &mut self,
-        _: String,
-        _: CasePreferences,
+        cases: String,
+        preferences: CasePreferences,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let cases = TextFilter::new(&self.config).truncate(&cases);
+        self.report_violations("cases", &cases.violations)?;
+
+        self.client.cases = cases.text;
+        self.client.case_preferences = preferences;
+        self.client_manager.lock().await.update_client(self.client.clone());
+        Ok(())
     }
 
+    /// Case manager looks for players for the case. Clients which want to
+    /// play one of the roles get notified.
     pub async fn handle_case_announce(
         &mut self,
-        _: String,
-        _: CasePreferences,
+        title: String,
+        needed: CasePreferences,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        if !area.case_managers.contains(&self.client.id) {
+            return self.send_server_message(
+                "You must be a case manager to announce a case.",
+            );
+        }
+        let title = TextFilter::new(&self.config).truncate(&title);
+        self.report_violations("case title", &title.violations)?;
+        let roles = needed.roles();
+        if roles.is_empty() {
+            return self.send_server_message(
+                "Choose at least one role the case needs.",
+            );
+        }
+
+        let char_name = client_manager
+            .character_name(self.client.char_id)
+            .unwrap_or("Spectator");
+        let message = format!(
+            "=== Case Announcement ===\n{} [{}] is hosting {} in {}, looking for {}.",
+            char_name,
+            self.client.id,
+            title.text,
+            area.name,
+            roles.join(", ")
+        );
+        let id = self.client.id;
+        let wants_case =
+            |c: &Client| c.id != id && c.case_preferences.overlaps(&needed);
+
+        client_manager.broadcast_filtered(
+            |c| c.features.casing_alerts && wants_case(c),
+            ServerCommand::CaseAnnounce(message.clone(), needed),
+        );
+        client_manager.broadcast_filtered(
+            |c| !c.features.casing_alerts && wants_case(c),
+            ServerCommand::OOCMessage(
+                self.config.general.hostname.clone(),
+                message,
+                true,
+            ),
+        );
+        Ok(())
     }
 
     pub async fn handle_penalties(
         &mut self,
-        _: u32,
-        _: u32,
+        bar: u32,
+        value: u32,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        if self.client.char_id == -1 {
+            return self
+                .send_server_message("Spectators can't change the penalties.");
+        }
+
+        let mut client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get_mut(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        if let Err(e) = area.set_penalty(bar, value) {
+            log::debug!(
+                "Rejected penalty from client {}: {}",
+                self.client.id,
+                e
+            );
+            return Ok(());
+        }
+        let area_name = area.name.clone();
+        client_manager.broadcast_area(
+            self.client.area,
+            ServerCommand::Penalties(bar, value),
+        );
+        drop(client_manager);
+
+        let side = if bar == 1 { "defense" } else { "prosecution" };
+        let message = format!("Set {} penalty to {}", side, value);
+        self.log_room_event(RoomEvent::Penalty, &area_name, &message).await
     }
 
     pub async fn handle_add_evidence(
         &mut self,
-        _: EvidenceArgs,
+        args: EvidenceArgs,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let args = self.filter_evidence(args)?;
+        let message = format!("Added evidence {:?}", args.name);
+        self.update_evidence(message, |evidence| {
+            evidence.push(args);
+            Ok(())
+        })
+        .await
     }
 
     pub async fn handle_delete_evidence(
         &mut self,
-        _: u32,
+        id: u32,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let message = format!("Deleted evidence {}", id);
+        self.update_evidence(message, |evidence| {
+            if id as usize >= evidence.len() {
+                anyhow::bail!("no evidence with ID {}", id);
+            }
+            evidence.remove(id as usize);
+            Ok(())
+        })
+        .await
     }
 
     pub async fn handle_edit_evidence(
         &mut self,
-        _: u32,
-        _: EvidenceArgs,
+        id: u32,
+        args: EvidenceArgs,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let args = self.filter_evidence(args)?;
+        let message = format!("Edited evidence {} to {:?}", id, args.name);
+        self.update_evidence(message, |evidence| {
+            let entry = evidence
+                .get_mut(id as usize)
+                .ok_or_else(|| anyhow::anyhow!("no evidence with ID {}", id))?;
+            *entry = args;
+            Ok(())
+        })
+        .await
+    }
+
+    fn filter_evidence(
+        &self,
+        args: EvidenceArgs,
+    ) -> Result<EvidenceArgs, anyhow::Error> {
+        let filter = TextFilter::new(&self.config);
+        let name = filter.truncate(&args.name);
+        let description = filter.truncate(&args.description);
+        let image = filter.truncate(&args.image);
+        self.report_violations("evidence name", &name.violations)?;
+        self.report_violations(
+            "evidence description",
+            &description.violations,
+        )?;
+        self.report_violations("evidence image", &image.violations)?;
+
+        Ok(EvidenceArgs {
+            name: name.text,
+            description: description.text,
+            image: image.text,
+        })
+    }
+
+    /// Changes the evidence of the client's area if its evidence mode
+    /// allows that, then shows the new list to everyone in the area and
+    /// logs `message` as a room event
+    async fn update_evidence(
+        &mut self,
+        message: String,
+        update: impl FnOnce(&mut Vec<EvidenceArgs>) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let mut client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get_mut(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        if !area.can_edit_evidence(self.client.id, self.client.privilege()) {
+            return self.send_server_message(
+                "You are not allowed to change the evidence in this area.",
+            );
+        }
+        if let Err(e) = update(&mut area.evidence) {
+            log::debug!(
+                "Rejected evidence change from client {}: {}",
+                self.client.id,
+                e
+            );
+            return Ok(());
+        }
+
+        let area_name = area.name.clone();
+        let evidence_list = area.evidence_list();
+        client_manager.broadcast_area(self.client.area, evidence_list);
+        drop(client_manager);
+
+        self.log_room_event(RoomEvent::Evidence, &area_name, &message).await
+    }
+
+    /// Logs room event done by this client in the area
+    async fn log_room_event(
+        &self,
+        event: RoomEvent,
+        room_name: &str,
+        message: &str,
+    ) -> Result<(), anyhow::Error> {
+        let char_name = usize::try_from(self.client.char_id)
+            .ok()
+            .and_then(|id| self.config.characters.get(id))
+            .map(String::as_str)
+            .unwrap_or_default();
+        let source = RoomEventSource {
+            ipid: self.client.ipid,
+            room_name,
+            char_name,
+            ooc_name: &self.client.name,
+        };
+        self.db.log_room_event(event, source, None, message).await
     }
 
     pub async fn handle_call_mod_button(
         &mut self,
-        _: String,
+        reason: Option<String>,
     ) -> Result<(), anyhow::Error> {
-        unimplemented!()
+        let reason = TextFilter::new(&self.config)
+            .truncate(reason.as_deref().unwrap_or_default());
+        self.report_violations("modcall reason", &reason.violations)?;
+
+        let mut client_manager = self.client_manager.lock().await;
+        let checked = self.client.modcall_floodguard.check();
+        client_manager.update_client(self.client.clone());
+
+        if let Err(left) = checked {
+            return self.send_server_message(format!(
+                "You called a moderator too often. You can do it again in {} seconds.",
+                left.as_secs()
+            ));
+        }
+
+        let area = client_manager
+            .areas
+            .get(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+        let char_name = client_manager
+            .character_name(self.client.char_id)
+            .unwrap_or("Spectator");
+        let reason = match reason.text.as_str() {
+            "" => "without reason".to_owned(),
+            text => format!("with reason: {}", text),
+        };
+        let message = format!(
+            "[{}] {} ({}) in {} {}",
+            self.client.id, char_name, self.client.ipid, area.name, reason
+        );
+        client_manager.broadcast_filtered(
+            |c| c.is_mod,
+            ServerCommand::ModCall(message.clone()),
+        );
+        drop(client_manager);
+
+        self.db
+            .log_misc_event(
+                MiscEvent::ModCall,
+                Some(self.client.ipid),
+                None,
+                &message,
+            )
+            .await?;
+        log::info!("Modcall: {}", message);
+        self.send_server_message("You have called a moderator.")
+    }
+}
+
+/// Checks WT/CE type sent by a client. Only verdicts have a variant:
+/// `0` for not guilty and `1` for guilty.
+fn validate_wtce(
+    kind: &str,
+    variant: Option<u32>,
+) -> Result<(), anyhow::Error> {
+    match (kind, variant) {
+        ("testimony1", None) | ("testimony2", None) => Ok(()),
+        ("judgeruling", Some(0)) | ("judgeruling", Some(1)) => Ok(()),
+        ("judgeruling", _) => anyhow::bail!("invalid verdict {:?}", variant),
+        (_, Some(_)) => anyhow::bail!("unexpected variant {:?}", variant),
+        _ => anyhow::bail!("unknown type"),
+    }
+}
+
+/// Whether an OOC name would pass for the server's. The hostname in the
+/// config is escaped, e.g. `<dollar>H` for `$H`.
+fn is_server_name(name: &str, hostname: &str) -> bool {
+    name.trim().eq_ignore_ascii_case(unescape(hostname.to_string()).trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_server_name() {
+        assert!(is_server_name("$H", "<dollar>H"));
+        assert!(is_server_name(" $h ", "<dollar>H"));
+        assert!(!is_server_name("Phoenix", "<dollar>H"));
     }
 }
@@ -3,13 +3,20 @@
 
 use std::io::{stdin, BufRead, Read};
 
+pub mod advertiser;
+pub mod area_manager;
 pub mod client_manager;
 pub mod command;
 pub mod config;
+pub mod connection_state;
+pub mod features;
+pub mod floodguard;
 pub mod handlers;
 pub mod master_server_client;
 pub mod networking;
+pub mod ooc_commands;
 pub mod server;
+pub mod text_filter;
 
 fn prompt(text: &str) -> bool {
     let mut answer = String::with_capacity(3);
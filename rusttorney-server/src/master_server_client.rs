@@ -1,27 +1,84 @@
 #![allow(unused)]
 use crate::config::Config;
+use crate::networking::codec::{
+    escape, escape_subargs, split_message, write_message,
+};
 use crate::networking::Command;
 use anyhow::Error;
+use bytes::BytesMut;
 use futures::future::BoxFuture;
-use serde::export::PhantomData;
+use std::marker::PhantomData;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Context;
+use std::time::Duration;
 use tokio::io::{AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::macros::support::Poll;
 use tokio::net::TcpStream;
 use tokio::stream::{Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
 
+#[rustfmt::skip]
 #[derive(Debug, Command)]
 pub enum MasterServerCommand {
     #[command(code = "CHECK")]
     Check,
+    #[command(code = "PING")]
+    Ping,
     #[command(code = "PONG")]
     Pong,
     #[command(code = "NOSERV")]
     NOSERV,
-    // Other(String), // TODO: discuss why this exists
+    #[command(code = "SCC")]
+    ServerInfo(String, String, String, String), /* SCC#<port:u32>&<ws_port:u32>?#<name:String>#
+                                                 * <description:String>#<software:String>#% */
+}
+
+/// Codec for the legacy master server protocol, which uses the same message
+/// format as the clients do. Messages the server doesn't know are skipped.
+pub struct MasterServerCodec;
+
+impl Decoder for MasterServerCodec {
+    type Item = MasterServerCommand;
+    type Error = anyhow::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some((cmd, args)) = split_message(src)? {
+            match MasterServerCommand::from_protocol(&cmd, args.into_iter()) {
+                Ok(command) => return Ok(Some(command)),
+                Err(e) => log::debug!(
+                    "Ignoring message from the master server: {}",
+                    e
+                ),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<MasterServerCommand> for MasterServerCodec {
+    type Error = anyhow::Error;
+
+    fn encode(
+        &mut self,
+        item: MasterServerCommand,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        // The ports in `SCC` are separated by `&`, the rest of the
+        // arguments is escaped in `pack_server_info`
+        let escape_arg = match item {
+            MasterServerCommand::ServerInfo(..) => escape_subargs,
+            _ => escape,
+        };
+        let args: Vec<_> =
+            item.extract_args().iter().map(|arg| escape_arg(arg)).collect();
+        write_message(item.ident(), &args, dst);
+        Ok(())
+    }
 }
 
 pub trait CommandReader:
@@ -35,12 +92,12 @@ impl<S: Stream<Item = Result<MasterServerCommand, tokio::io::Error>>>
 }
 
 pub struct TcpCommandReader {
-    reader: ReadHalf<TcpStream>,
+    reader: FramedRead<ReadHalf<TcpStream>, MasterServerCodec>,
 }
 
 impl TcpCommandReader {
     pub fn new(reader: ReadHalf<TcpStream>) -> Self {
-        Self { reader }
+        Self { reader: FramedRead::new(reader, MasterServerCodec) }
     }
 }
 
@@ -48,10 +105,16 @@ impl Stream for TcpCommandReader {
     type Item = Result<MasterServerCommand, tokio::io::Error>;
 
     fn poll_next(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        unimplemented!()
+        Pin::new(&mut self.reader).poll_next(cx).map(|item| {
+            item.map(|res| {
+                res.map_err(|e| {
+                    tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e)
+                })
+            })
+        })
     }
 }
 
@@ -65,6 +128,8 @@ where
     software: &'a str,
     reader: R,
     writer: W,
+    /// Whether the master server got to know the server or checked on it
+    exchanged: bool,
 }
 
 #[derive(Debug)]
@@ -84,9 +149,22 @@ where
         reader: R,
         writer: W,
     ) -> Self {
-        MasterServerClient { config, software, reader, writer }
+        MasterServerClient {
+            config,
+            software,
+            reader,
+            writer,
+            exchanged: false,
+        }
     }
 
+    /// Whether the master server sent `NOSERV` or `CHECK` and was answered,
+    /// as opposed to e.g. closing the connection right away
+    pub fn exchanged(&self) -> bool {
+        self.exchanged
+    }
+
+    /// Answers the master server until the connection breaks
     pub async fn connection_loop(&mut self) -> Result<(), anyhow::Error> {
         let mut state = MasterServerClientState::WaitCommand;
         loop {
@@ -99,49 +177,64 @@ where
                 })??;
             match mes {
                 MasterServerCommand::Check => {
-                    self.send_message("PING#%").await?;
+                    self.send(MasterServerCommand::Ping).await?;
+                    self.exchanged = true;
                     state = MasterServerClientState::WaitPong;
                 }
-                MasterServerCommand::Pong => {
-                    match state {
-                        MasterServerClientState::WaitPong => {
-                            state = MasterServerClientState::WaitCommand;
-                        }
-                        MasterServerClientState::WaitCommand => { /* TODO: log this */
-                        }
+                MasterServerCommand::Pong => match state {
+                    MasterServerClientState::WaitPong => {
+                        state = MasterServerClientState::WaitCommand;
                     }
-                }
+                    MasterServerClientState::WaitCommand => {
+                        log::debug!("Unexpected PONG from the master server");
+                    }
+                },
                 MasterServerCommand::NOSERV => {
-                    self.send_message(self.pack_server_info()).await?;
-                } // MasterServerCommand::Other(_mes) => { /* TODO: log this */ }
+                    self.send(self.pack_server_info()).await?;
+                    self.exchanged = true;
+                }
+                other => log::debug!(
+                    "Unexpected command from the master server: {:?}",
+                    other
+                ),
             }
         }
     }
 
+    pub async fn send(
+        &mut self,
+        command: MasterServerCommand,
+    ) -> Result<(), anyhow::Error> {
+        let mut buf = BytesMut::new();
+        MasterServerCodec.encode(command, &mut buf)?;
+        self.send_message(std::str::from_utf8(&buf)?).await?;
+        Ok(())
+    }
+
     pub async fn send_message<T: AsRef<str>>(
         &mut self,
         message: T,
     ) -> Result<(), tokio::io::Error> {
-        self.writer.write(message.as_ref().as_bytes()).await?;
+        self.writer.write_all(message.as_ref().as_bytes()).await?;
         self.writer.flush().await?;
         Ok(())
     }
 
-    fn pack_server_info(&self) -> String {
+    /// `SCC` with the ports clients connect to
+    fn pack_server_info(&self) -> MasterServerCommand {
         let cfg = &self.config;
-        let port = match cfg.general.websocket_port {
-            Some(wsport) => format!("{}&{}", cfg.masterserver.port, wsport),
-            _ => format!("{}", cfg.masterserver.port),
+        let websocket_port =
+            cfg.general.websocket_port.filter(|_| cfg.general.use_websockets);
+        let port = match websocket_port {
+            Some(wsport) => format!("{}&{}", cfg.general.port, wsport),
+            _ => format!("{}", cfg.general.port),
         };
-        format!(
-            "SCC#{}#{}#{}#{}#%",
+        MasterServerCommand::ServerInfo(
             port,
-            cfg.masterserver.name,
-            cfg.masterserver.description,
-            self.software
+            escape(&cfg.masterserver.name),
+            escape(&cfg.masterserver.description),
+            escape(self.software),
         )
-        // TODO: do this with parser struct
-        // TODO 2: add needed enum variant and `to_message()` it
     }
 }
 
@@ -162,3 +255,155 @@ impl<'a> MasterServerClient<'a, TcpCommandReader, WriteHalf<TcpStream>> {
         Ok(Self::new(config, software, TcpCommandReader::new(reader), writer))
     }
 }
+
+/// Delays between reconnection attempts, doubling up to `max`
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, current: initial }
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, self.max);
+        delay
+    }
+
+    /// Starts over after a successful attempt
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Keeps the server listed on the master server, reconnecting whenever the
+/// connection is lost
+pub async fn advertise(config: Arc<Config>, software: String) {
+    let backoff =
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(5 * 60));
+    advertise_with(config, &software, backoff).await
+}
+
+async fn advertise_with(
+    config: Arc<Config>,
+    software: &str,
+    mut backoff: Backoff,
+) {
+    loop {
+        match MasterServerClient::from_config_with_connect(
+            config.clone(),
+            software,
+        )
+        .await
+        {
+            Ok(mut client) => {
+                log::info!("Connected to the master server");
+                if let Err(e) = client.connection_loop().await {
+                    log::warn!("Lost connection to the master server: {}", e);
+                }
+                // A master server which accepts and hangs up right away
+                // shouldn't make us reconnect in a tight loop
+                if client.exchanged() {
+                    backoff.reset();
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to connect to the master server: {}", e)
+            }
+        }
+
+        let delay = backoff.next_delay();
+        log::info!("Reconnecting to the master server in {:?}", delay);
+        tokio::time::delay_for(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::test_config;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    #[test]
+    fn double_backoff() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<_> =
+            (0..4).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    /// Reads messages of the client until `%`
+    async fn read_message(reader: &mut BufReader<TcpStream>) -> String {
+        let mut buf = Vec::new();
+        reader.read_until(b'%', &mut buf).await.unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn advertise_to_fake_master_server() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = test_config();
+        config.general.port = 27017;
+        config.masterserver.ip = "127.0.0.1".into();
+        config.masterserver.port = listener.local_addr().unwrap().port();
+        let backoff =
+            Backoff::new(Duration::from_millis(10), Duration::from_millis(10));
+        tokio::spawn(async move {
+            advertise_with(Arc::new(config), "rusttorney", backoff).await
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut master = BufReader::new(socket);
+        master.get_mut().write_all(b"NOSERV#%").await.unwrap();
+        assert_eq!(
+            read_message(&mut master).await,
+            "SCC#27017&50001#My server#My server description!#rusttorney#%"
+        );
+        master.get_mut().write_all(b"CHECK#%").await.unwrap();
+        assert_eq!(read_message(&mut master).await, "PING#%");
+        drop(master);
+
+        // The client comes back after the connection is lost
+        let accepted = timeout(Duration::from_secs(5), listener.accept());
+        let (socket, _) = accepted.await.unwrap().unwrap();
+        let mut master = BufReader::new(socket);
+        master.get_mut().write_all(b"UNKNOWN#%CHECK#%").await.unwrap();
+        assert_eq!(read_message(&mut master).await, "PING#%");
+    }
+
+    #[tokio::test]
+    async fn back_off_when_master_server_hangs_up() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = test_config();
+        config.masterserver.ip = "127.0.0.1".into();
+        config.masterserver.port = listener.local_addr().unwrap().port();
+        let backoff =
+            Backoff::new(Duration::from_millis(20), Duration::from_secs(1));
+        tokio::spawn(async move {
+            advertise_with(Arc::new(config), "rusttorney", backoff).await
+        });
+
+        // Accepted connections which are closed right away don't count as
+        // successful, so the delays keep growing: 20, 40, 80 ms
+        let mut accepted_at = Vec::new();
+        for _ in 0..4 {
+            let accepted = timeout(Duration::from_secs(5), listener.accept());
+            drop(accepted.await.unwrap().unwrap());
+            accepted_at.push(std::time::Instant::now());
+        }
+        let last_delay = accepted_at[3] - accepted_at[2];
+        assert!(last_delay >= Duration::from_millis(80), "{:?}", last_delay);
+    }
+}
@@ -31,8 +31,15 @@ use tokio_util::codec::{Decoder, Encoder};
 ///
 /// request = command-name, '#', args, the-end;
 /// ```
+///
+/// Characters with special meaning in the protocol are escaped inside the
+/// arguments, see [`ESCAPES`].
 pub struct AOMessageCodec;
 
+/// Escape sequences used by AO clients for the protocol's special characters
+pub const ESCAPES: [(char, &str); 4] =
+    [('#', "<num>"), ('%', "<percent>"), ('$', "<dollar>"), ('&', "<and>")];
+
 impl Decoder for AOMessageCodec {
     type Item = ClientCommand;
     type Error = anyhow::Error;
@@ -41,39 +48,12 @@ impl Decoder for AOMessageCodec {
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        const ARG_SEP: u8 = b'#';
-        const MSG_END: &[u8] = b"#%";
-
-        if src.len() > 8192 {
-            // spam protection? Copied from legacy server
-            return Err(anyhow::anyhow!("Too much data"));
+        match split_message(src)? {
+            Some((cmd, args)) => {
+                Ok(Some(ClientCommand::from_protocol(&cmd, args.into_iter())?))
+            }
+            None => Ok(None),
         }
-
-        // Find the end of AO message
-        let msg_end = match src.windows(2).position(|s| s == MSG_END) {
-            Some(idx) => idx,
-            None => return Ok(None),
-        };
-
-        // Take message from the buffer
-        let mut msg = src.split_to(msg_end);
-        // Forget message separator
-        src.advance(MSG_END.len());
-
-        // Find the end of command name in message
-        let cmd_end =
-            msg.iter().position(|&c| c == ARG_SEP).unwrap_or_else(|| msg.len());
-        // Take the command name
-        let cmd_raw = msg.split_to(cmd_end);
-        let cmd = ignore_ill_utf8(&cmd_raw[..]);
-
-        // Divide rest of the message into chunks.
-        // If there are any arguments in the slice, it starts with '#'.
-        // `.skip(1)` ignores the empty string appearing because of it
-        let args_iter =
-            msg.as_ref().split(|&c| c == ARG_SEP).skip(1).map(ignore_ill_utf8);
-
-        Ok(Some(ClientCommand::from_protocol(&cmd, args_iter)?))
     }
 
     fn decode_eof(
@@ -101,37 +81,114 @@ impl Encoder<ServerCommand> for AOMessageCodec {
         item: ServerCommand,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let args_len =
-            item.extract_args().iter().fold(0, |i, s| i + s.len() + 1);
-        let ident = item.ident();
-        #[rustfmt::skip]
-            let reserve_len =
-            // 2 - 8
-            ident.len() +
-                // #
-                1 +
-                // args_len is every arg + #
-                args_len +
-                // %
-                1;
-        dst.reserve(reserve_len);
-        dst.put(ident.as_bytes());
+        let escape_arg =
+            if item.has_subargs() { escape_subargs } else { escape };
+        let args: Vec<_> =
+            item.extract_args().iter().map(|arg| escape_arg(arg)).collect();
+        write_message(item.ident(), &args, dst);
+        Ok(())
+    }
+}
+
+/// Takes the next AO message out of the buffer, split into the command
+/// name and the unescaped arguments. Returns `None` until the whole
+/// message is received.
+pub fn split_message(
+    src: &mut BytesMut,
+) -> Result<Option<(String, Vec<String>)>, anyhow::Error> {
+    const ARG_SEP: u8 = b'#';
+    const MSG_END: &[u8] = b"#%";
+
+    if src.len() > 8192 {
+        // spam protection? Copied from legacy server
+        return Err(anyhow::anyhow!("Too much data"));
+    }
+
+    // Find the end of AO message
+    let msg_end = match src.windows(2).position(|s| s == MSG_END) {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+
+    // Take message from the buffer
+    let mut msg = src.split_to(msg_end);
+    // Forget message separator
+    src.advance(MSG_END.len());
+
+    // Find the end of command name in message
+    let cmd_end =
+        msg.iter().position(|&c| c == ARG_SEP).unwrap_or_else(|| msg.len());
+    // Take the command name
+    let cmd_raw = msg.split_to(cmd_end);
+    let cmd = ignore_ill_utf8(&cmd_raw[..]);
+
+    // Divide rest of the message into chunks.
+    // If there are any arguments in the slice, it starts with '#'.
+    // `.skip(1)` ignores the empty string appearing because of it
+    let args = msg
+        .as_ref()
+        .split(|&c| c == ARG_SEP)
+        .skip(1)
+        .map(|arg| unescape(ignore_ill_utf8(arg)))
+        .collect();
+
+    Ok(Some((cmd, args)))
+}
+
+/// Writes AO message with already escaped arguments
+pub fn write_message(ident: &str, args: &[String], dst: &mut BytesMut) {
+    let args_len = args.iter().fold(0, |i, s| i + s.len() + 1);
+    #[rustfmt::skip]
+        let reserve_len =
+        // 2 - 8
+        ident.len() +
+            // #
+            1 +
+            // args_len is every arg + #
+            args_len +
+            // %
+            1;
+    dst.reserve(reserve_len);
+    dst.put(ident.as_bytes());
+    dst.put_u8(b'#');
+
+    for arg in args {
+        dst.put(arg.as_bytes());
         dst.put_u8(b'#');
+    }
+
+    dst.put_u8(b'%');
+}
 
-        for arg in item.extract_args() {
-            dst.put(arg.as_bytes());
-            dst.put_u8(b'#');
+/// Replaces the protocol's special characters with escape sequences
+pub fn escape(arg: &str) -> String {
+    ESCAPES.iter().fold(arg.to_owned(), |arg, (c, seq)| {
+        if arg.contains(*c) {
+            arg.replace(*c, seq)
+        } else {
+            arg
         }
+    })
+}
 
-        dst.put_u8(b'%');
-        Ok(())
+/// Like [`escape`], but keeps `&`, which separates sub-arguments. The
+/// sub-arguments themselves have to be escaped beforehand.
+pub fn escape_subargs(arg: &str) -> String {
+    arg.split('&').map(escape).collect::<Vec<_>>().join("&")
+}
+
+/// Replaces escape sequences with the characters they stand for
+pub fn unescape(arg: String) -> String {
+    if !arg.contains('<') {
+        return arg;
     }
+    ESCAPES.iter().fold(arg, |arg, (c, seq)| arg.replace(seq, &c.to_string()))
 }
 
 fn ignore_ill_utf8(v: &[u8]) -> String {
     use std::char::REPLACEMENT_CHARACTER;
 
-    let str = String::from_utf8_lossy(&v);
+    let str = String::from_utf8_lossy(v);
 
     match str {
         Cow::Owned(mut own) => {
@@ -145,7 +202,7 @@ fn ignore_ill_utf8(v: &[u8]) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::command::{ClientCommand, ServerCommand};
+    use crate::command::{CasePreferences, ClientCommand, ServerCommand};
     use bytes::BytesMut;
 
     #[test]
@@ -165,6 +222,37 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn parse_escaped_args() {
+        let mut input = b"HI#<num>1<percent><dollar>H<and><and>#%"[..].into();
+        let expected = ClientCommand::Handshake("#1%$H&&".into());
+        let actual = AOMessageCodec.decode(&mut input).unwrap().unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn encode_escaped_args() {
+        let command = ServerCommand::BanReason("100% #1 & $5".into());
+        let mut actual = BytesMut::new();
+        let expected =
+            BytesMut::from(&b"BD#100<percent> <num>1 <and> <dollar>5#%"[..]);
+        AOMessageCodec.encode(command, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn escaping_prevents_injection() {
+        let command = ServerCommand::Handshake("x#%BD#pwned".into());
+        let mut encoded = BytesMut::new();
+        AOMessageCodec.encode(command, &mut encoded).unwrap();
+        assert_eq!(&encoded[..], &b"HI#x<num><percent>BD<num>pwned#%"[..]);
+
+        let mut decoded = encoded;
+        let actual = AOMessageCodec.decode(&mut decoded).unwrap().unwrap();
+        assert_eq!(actual, ClientCommand::Handshake("x#%BD#pwned".into()));
+        assert!(decoded.is_empty());
+    }
+
     #[test]
     fn mismatched_number_of_args() {
         let mut input1 = b"HI#%"[..].into();
@@ -173,6 +261,206 @@ mod tests {
         assert!(AOMessageCodec.decode(&mut input2).is_err());
     }
 
+    #[test]
+    fn parse_overloaded_music_list() {
+        let mut src = b"AM#%AM#3#%"[..].into();
+        let mut codec = AOMessageCodec;
+        let actual1 = codec.decode(&mut src).unwrap().unwrap();
+        let actual2 = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(actual1, ClientCommand::AO2MusicList);
+        assert_eq!(actual2, ClientCommand::MusicList(3));
+    }
+
+    #[test]
+    fn parse_spectator_selection() {
+        let mut input = b"CC#0#-1#hdid#%"[..].into();
+        let expected = ClientCommand::SelectCharacter(0, -1, "hdid".into());
+        let actual = AOMessageCodec.decode(&mut input).unwrap().unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_play_song() {
+        let mut src = b"MC#Objection.opus#2#%MC#Lobby#-1#Nick#1#%"[..].into();
+        let mut codec = AOMessageCodec;
+        let actual1 = codec.decode(&mut src).unwrap().unwrap();
+        let actual2 = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            actual1,
+            ClientCommand::PlaySong("Objection.opus".into(), 2, None, None)
+        );
+        assert_eq!(
+            actual2,
+            ClientCommand::PlaySong(
+                "Lobby".into(),
+                -1,
+                Some("Nick".into()),
+                Some(1)
+            )
+        );
+    }
+
+    #[test]
+    fn encode_play_song() {
+        let command = ServerCommand::PlaySong(
+            "Objection.opus".into(),
+            2,
+            "Nick".into(),
+            true,
+            0,
+            Some(0),
+        );
+        let mut actual = BytesMut::new();
+        let expected = BytesMut::from(&b"MC#Objection.opus#2#Nick#1#0#0#%"[..]);
+        AOMessageCodec.encode(command, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_client_version() {
+        let mut input = b"ID#AO2#2.8.5#%"[..].into();
+        let expected =
+            ClientCommand::ClientVersion("AO2".into(), "2.8.5".into());
+        let actual = AOMessageCodec.decode(&mut input).unwrap().unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_wtce_verdict() {
+        let mut src = b"RT#testimony1#%RT#judgeruling#1#%"[..].into();
+        let mut codec = AOMessageCodec;
+        let actual1 = codec.decode(&mut src).unwrap().unwrap();
+        let actual2 = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            actual1,
+            ClientCommand::WTCEButtons("testimony1".into(), None)
+        );
+        assert_eq!(
+            actual2,
+            ClientCommand::WTCEButtons("judgeruling".into(), Some(1))
+        );
+    }
+
+    #[test]
+    fn parse_call_mod_button() {
+        let mut src = b"ZZ#%ZZ#Spam in Basement#%"[..].into();
+        let mut codec = AOMessageCodec;
+        let actual1 = codec.decode(&mut src).unwrap().unwrap();
+        let actual2 = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(actual1, ClientCommand::CallModButton(None));
+        assert_eq!(
+            actual2,
+            ClientCommand::CallModButton(Some("Spam in Basement".into()))
+        );
+    }
+
+    #[test]
+    fn encode_evidence_list() {
+        let command = ServerCommand::EvidenceList(vec![
+            format!("{}&Found at the scene&knife.png", escape("Knife & fork")),
+            "Autopsy report&&".into(),
+        ]);
+        let mut actual = BytesMut::new();
+        let expected = BytesMut::from(
+            &b"LE#Knife <and> fork&Found at the scene&knife.png#Autopsy report&&#%"[..],
+        );
+        AOMessageCodec.encode(command, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_case_preferences() {
+        let mut input = b"SETCASE#Turnabout#1#0#1#0#0#1#%"[..].into();
+        let expected = ClientCommand::SetCasePreferences(
+            "Turnabout".into(),
+            CasePreferences {
+                cm: true,
+                def: false,
+                pro: true,
+                judge: false,
+                jury: false,
+                steno: true,
+            },
+        );
+        let actual = AOMessageCodec.decode(&mut input).unwrap().unwrap();
+        assert_eq!(actual, expected);
+
+        let mut input = b"SETCASE#Turnabout#true#0#1#0#0#1#%"[..].into();
+        assert!(AOMessageCodec.decode(&mut input).is_err());
+    }
+
+    #[test]
+    fn encode_case_announce() {
+        let needed = CasePreferences::from_roles(&["def", "judge"]).unwrap();
+        let command = ServerCommand::CaseAnnounce("Turnabout".into(), needed);
+        let mut actual = BytesMut::new();
+        let expected = BytesMut::from(&b"CASEA#Turnabout#0#1#0#1#0#0#%"[..]);
+        AOMessageCodec.encode(command, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+
+        let judge = CasePreferences::from_roles(&["judge"]).unwrap();
+        let steno = CasePreferences::from_roles(&["steno"]).unwrap();
+        assert!(judge.overlaps(&needed));
+        assert!(!steno.overlaps(&needed));
+        assert!(CasePreferences::from_roles(&["lawyer"]).is_err());
+    }
+
+    #[test]
+    fn parse_ic_message() {
+        let mut old =
+            b"MS#chat#-#Phoenix#normal#Hold it!#def#0#0#3#0#1#0#0#0#2#%"[..]
+                .into();
+        let mut new = b"MS#chat#-#Phoenix#normal#Hold it!#def#0#0#3#0#1#0#1#0#2#Nick#5^0#10&0#1#0#1#-#-#-#0#rain#%"[..].into();
+
+        let old = match AOMessageCodec.decode(&mut old).unwrap().unwrap() {
+            ClientCommand::ICMessage(args) => args,
+            other => panic!("Expected MS, got {:?}", other),
+        };
+        assert_eq!(old.folder, "Phoenix");
+        assert_eq!(old.char_id, 3);
+        assert_eq!(old.text_color, 2);
+        assert_eq!(old.other_charid, -1);
+
+        let new = match AOMessageCodec.decode(&mut new).unwrap().unwrap() {
+            ClientCommand::ICMessage(args) => args,
+            other => panic!("Expected MS, got {:?}", other),
+        };
+        assert!(new.flip);
+        assert_eq!(new.showname, "Nick");
+        assert_eq!(new.other_charid, 5);
+        assert_eq!(new.self_offset, "10&0");
+        assert!(new.noninterrupting_preanim);
+        assert!(new.screenshake);
+        assert_eq!(new.effect, "rain");
+    }
+
+    #[test]
+    fn reject_short_ic_message() {
+        let mut input = b"MS#chat#-#Phoenix#normal#Hold it!#def#%"[..].into();
+        assert!(AOMessageCodec.decode(&mut input).is_err());
+    }
+
+    #[test]
+    fn encode_ic_message() {
+        let mut input =
+            b"MS#chat#-#Phoenix#normal#Hi#def#0#0#3#0#1#0#0#0#2#%"[..].into();
+        let mut args = match AOMessageCodec.decode(&mut input).unwrap().unwrap()
+        {
+            ClientCommand::ICMessage(args) => args,
+            other => panic!("Expected MS, got {:?}", other),
+        };
+        args.other_charid = 5;
+        args.other_name = "Edgeworth".into();
+        let mut actual = BytesMut::new();
+        let expected = BytesMut::from(
+            &b"MS#chat#-#Phoenix#normal#Hi#def#0#0#3#0#1#0#0#0#2##5#Edgeworth##0#0#0#0#0#0####0##%"[..],
+        );
+        AOMessageCodec
+            .encode(ServerCommand::ICMessage(args), &mut actual)
+            .unwrap();
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn two_messages_in_one_chunk() {
         let mut src = b"HI#hdid1#%HI#hdid2#%"[..].into();
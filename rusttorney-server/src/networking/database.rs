@@ -1,7 +1,81 @@
+use chrono::NaiveDateTime;
 use deadpool::managed::{Object, PoolError};
 use deadpool_postgres::{ClientWrapper, Pool};
 use std::net::IpAddr;
-use tokio_postgres::{Error, NoTls};
+use std::path::{Path, PathBuf};
+use tokio_postgres::{Error, NoTls, Row};
+
+/// Ban of IPIDs and/or HDIDs, as stored in the `bans` table
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub id: i32,
+    pub reason: String,
+    /// IPID of the moderator who issued the ban
+    pub banned_by: Option<u32>,
+    /// `None` for bans from before the date was filled in
+    pub ban_date: Option<NaiveDateTime>,
+    /// UTC time when the ban expires, `None` for permanent bans
+    pub unban_date: Option<NaiveDateTime>,
+}
+
+impl Ban {
+    const COLUMNS: &'static str =
+        "bans.ban_id, bans.reason, bans.banned_by, bans.ban_date, bans.unban_date";
+    /// Bans which haven't expired or been lifted yet
+    const ACTIVE: &'static str =
+        "(bans.unban_date IS NULL OR bans.unban_date > now() AT TIME ZONE 'UTC')";
+
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get(0_usize),
+            reason: row.get::<_, Option<String>>(1_usize).unwrap_or_default(),
+            banned_by: row
+                .get::<_, Option<i32>>(2_usize)
+                .map(|ipid| ipid as u32),
+            ban_date: row.get(3_usize),
+            unban_date: row.get(4_usize),
+        }
+    }
+
+    /// Text of the `BD` packet shown to the banned client
+    pub fn message(&self) -> String {
+        format!("{}\nID: {}\nUntil: {}", self.reason, self.id, self.until())
+    }
+
+    /// Expiry date in a human readable form
+    pub fn until(&self) -> String {
+        match self.unban_date {
+            Some(date) => date.format("%Y-%m-%d %H:%M UTC").to_string(),
+            None => "forever".into(),
+        }
+    }
+}
+
+/// Types of `misc_events`, as inserted by migration v5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiscEvent {
+    System = 1,
+    Kick = 2,
+    Ban = 3,
+    Unban = 4,
+    ModCall = 5,
+}
+
+/// Types of `room_events`, as inserted by migration v6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomEvent {
+    Penalty = 3,
+    Evidence = 15,
+}
+
+/// Who did a room event and where
+#[derive(Debug, Clone, Copy)]
+pub struct RoomEventSource<'a> {
+    pub ipid: u32,
+    pub room_name: &'a str,
+    pub char_name: &'a str,
+    pub ooc_name: &'a str,
+}
 
 /// Db pool uses Arc inside, so no need to wrap it in one as well.
 #[derive(Clone)]
@@ -20,6 +94,26 @@ impl DbWrapper {
         Self { db_pool }
     }
 
+    /// Runs the migrations from `dir` which are newer than `version`, each
+    /// in its own transaction. Returns the version the database ends up at.
+    pub async fn migrate(
+        &self,
+        dir: &Path,
+        version: i32,
+    ) -> Result<i32, anyhow::Error> {
+        let mut conn = self.get().await?;
+        for (_, migration) in pending_migrations(dir, version)? {
+            log::debug!("Executing migration: {:?}", &migration);
+            let migration_stmt = std::fs::read_to_string(migration)?;
+            let tx = conn.transaction().await?;
+            tx.batch_execute(&migration_stmt).await?;
+            tx.commit().await?;
+        }
+        let row =
+            conn.query_one("SELECT db_version FROM general_info", &[]).await?;
+        Ok(row.get(0_usize))
+    }
+
     pub async fn ipid(&self, ip: IpAddr) -> Result<i32, anyhow::Error> {
         let ip_str = ip.to_string();
         let conn = self.get().await?;
@@ -49,4 +143,333 @@ impl DbWrapper {
         tx.execute("INSERT INTO hdids (hdid, ipid) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&hdid, &ipid]).await?;
         tx.commit().await.map_err(Into::into)
     }
+
+    /// HDIDs which were ever used from the IPID
+    pub async fn hdids(&self, ipid: u32) -> Result<Vec<String>, anyhow::Error> {
+        let conn = self.get().await?;
+        let ipid = ipid as i32;
+        let rows = conn
+            .query("SELECT hdid FROM hdids WHERE ipid = $1", &[&ipid])
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0_usize)).collect())
+    }
+
+    /// Records an event done by `ipid` (`None` for the server itself) to
+    /// `target_ipid`
+    pub async fn log_misc_event(
+        &self,
+        event: MiscEvent,
+        ipid: Option<u32>,
+        target_ipid: Option<u32>,
+        data: &str,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.get().await?;
+        let ipid = ipid.map(|ipid| ipid as i32);
+        let target_ipid = target_ipid.map(|ipid| ipid as i32);
+        conn.execute(
+            "INSERT INTO misc_events (ipid, target_ipid, event_subtype, event_data) \
+             VALUES ($1, $2, $3, $4)",
+            &[&ipid, &target_ipid, &(event as i32), &data],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Records a login attempt, `profile_name` being `None` if it failed
+    pub async fn log_login(
+        &self,
+        ipid: u32,
+        profile_name: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.get().await?;
+        let ipid = ipid as i32;
+        conn.execute(
+            "INSERT INTO login_events (ipid, profile_name) VALUES ($1, $2)",
+            &[&ipid, &profile_name],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn log_room_event(
+        &self,
+        event: RoomEvent,
+        source: RoomEventSource<'_>,
+        target_ipid: Option<u32>,
+        message: &str,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.get().await?;
+        let ipid = source.ipid as i32;
+        let target_ipid = target_ipid.map(|ipid| ipid as i32);
+        conn.execute(
+            "INSERT INTO room_events \
+             (ipid, target_ipid, room_name, char_name, ooc_name, event_subtype, message) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &ipid,
+                &target_ipid,
+                &source.room_name,
+                &source.char_name,
+                &source.ooc_name,
+                &(event as i32),
+                &message,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Bans the IPID and its HDIDs, all in one transaction. Returns the
+    /// new ban.
+    pub async fn ban(
+        &self,
+        ipid: u32,
+        hdids: &[String],
+        reason: &str,
+        unban_date: Option<NaiveDateTime>,
+        banned_by: Option<u32>,
+    ) -> Result<Ban, anyhow::Error> {
+        let mut conn = self.get().await?;
+        let tx = conn.transaction().await?;
+        let banned_by = banned_by.map(|ipid| ipid as i32);
+        let query = format!(
+            "INSERT INTO bans (ban_date, unban_date, banned_by, reason) \
+             VALUES (now() AT TIME ZONE 'UTC', $1, $2, $3) RETURNING {}",
+            Ban::COLUMNS
+        );
+        let row = tx
+            .query_one(query.as_str(), &[&unban_date, &banned_by, &reason])
+            .await?;
+        let ban = Ban::from_row(&row);
+
+        tx.execute(
+            "INSERT INTO ip_bans (ipid, ban_id) VALUES ($1, $2) \
+             ON CONFLICT (ipid) DO UPDATE SET ban_id = EXCLUDED.ban_id",
+            &[&(ipid as i32), &ban.id],
+        )
+        .await?;
+        for hdid in hdids {
+            tx.execute(
+                "INSERT INTO hdid_bans (hdid, ban_id) VALUES ($1, $2) \
+                 ON CONFLICT (hdid) DO UPDATE SET ban_id = EXCLUDED.ban_id",
+                &[hdid, &ban.id],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(ban)
+    }
+
+    /// Active ban of the IPID, if any
+    pub async fn ipid_ban(
+        &self,
+        ipid: u32,
+    ) -> Result<Option<Ban>, anyhow::Error> {
+        let conn = self.get().await?;
+        let ipid = ipid as i32;
+        let query = format!(
+            "SELECT {} FROM bans JOIN ip_bans ON bans.ban_id = ip_bans.ban_id \
+             WHERE ip_bans.ipid = $1 AND {}",
+            Ban::COLUMNS,
+            Ban::ACTIVE
+        );
+        let row = conn.query_opt(query.as_str(), &[&ipid]).await?;
+        Ok(row.as_ref().map(Ban::from_row))
+    }
+
+    /// Active ban of the HDID, if any
+    pub async fn hdid_ban(
+        &self,
+        hdid: &str,
+    ) -> Result<Option<Ban>, anyhow::Error> {
+        let conn = self.get().await?;
+        let query = format!(
+            "SELECT {} FROM bans JOIN hdid_bans ON bans.ban_id = hdid_bans.ban_id \
+             WHERE hdid_bans.hdid = $1 AND {}",
+            Ban::COLUMNS,
+            Ban::ACTIVE
+        );
+        let row = conn.query_opt(query.as_str(), &[&hdid]).await?;
+        Ok(row.as_ref().map(Ban::from_row))
+    }
+
+    /// Bans which are in effect now, with the IPIDs they cover
+    pub async fn active_bans(
+        &self,
+    ) -> Result<Vec<(Ban, Vec<u32>)>, anyhow::Error> {
+        let conn = self.get().await?;
+        let query = format!(
+            "SELECT {}, ARRAY(SELECT ipid FROM ip_bans WHERE ip_bans.ban_id = bans.ban_id) \
+             FROM bans WHERE {} ORDER BY bans.ban_id",
+            Ban::COLUMNS,
+            Ban::ACTIVE
+        );
+        let rows = conn.query(query.as_str(), &[]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let ipids: Vec<i32> = row.get(5_usize);
+                (
+                    Ban::from_row(row),
+                    ipids.into_iter().map(|ipid| ipid as u32).collect(),
+                )
+            })
+            .collect())
+    }
+
+    /// Lifts the ban by making it expire now. Returns the IPIDs the ban
+    /// covered, or `None` if there is no such active ban.
+    pub async fn lift_ban(
+        &self,
+        ban_id: i32,
+    ) -> Result<Option<Vec<u32>>, anyhow::Error> {
+        let conn = self.get().await?;
+        let query = format!(
+            "UPDATE bans SET unban_date = now() AT TIME ZONE 'UTC' \
+             WHERE bans.ban_id = $1 AND {} \
+             RETURNING ARRAY(SELECT ipid FROM ip_bans WHERE ip_bans.ban_id = bans.ban_id)",
+            Ban::ACTIVE
+        );
+        let row = conn.query_opt(query.as_str(), &[&ban_id]).await?;
+        Ok(row.map(|row| {
+            let ipids: Vec<i32> = row.get(0_usize);
+            ipids.into_iter().map(|ipid| ipid as u32).collect()
+        }))
+    }
+}
+
+/// Migrations `v<version>.sql` in `dir` newer than `version`, oldest first
+fn pending_migrations(
+    dir: &Path,
+    version: i32,
+) -> Result<Vec<(i32, PathBuf)>, anyhow::Error> {
+    let mut migrations = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix('v')?.strip_suffix(".sql"))
+            .and_then(|number| number.parse::<i32>().ok());
+        match number {
+            Some(number) if number > version => migrations.push((number, path)),
+            Some(_) => {}
+            None => log::warn!("Skipping {:?}, not a migration", path),
+        }
+    }
+    migrations.sort();
+    Ok(migrations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use deadpool_postgres::Config as PgConfig;
+
+    fn migrations_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../migrations")
+    }
+
+    /// Empty database `name` on the local PostgreSQL server
+    async fn scratch_db(name: &str) -> DbWrapper {
+        let (admin, connection) =
+            tokio_postgres::connect("host=localhost user=postgres", NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        for stmt in &["DROP DATABASE IF EXISTS", "CREATE DATABASE"] {
+            let query = format!("{} {}", stmt, name);
+            admin.batch_execute(query.as_str()).await.unwrap();
+        }
+
+        let mut pg_config = PgConfig::new();
+        pg_config.host = Some("localhost".into());
+        pg_config.user = Some("postgres".into());
+        pg_config.dbname = Some(name.into());
+        DbWrapper::new(pg_config.create_pool(NoTls).unwrap())
+    }
+
+    #[test]
+    fn list_pending_migrations() {
+        let pending = pending_migrations(&migrations_dir(), 3).unwrap();
+        let versions: Vec<_> = pending.iter().map(|(v, _)| *v).collect();
+
+        assert_eq!(versions[..2], [4, 5]);
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(pending[0].1.ends_with("v4.sql"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local PostgreSQL server"]
+    async fn upgrade_from_v3() {
+        let db = scratch_db("rusttorney_upgrade_test").await;
+        let conn = db.get().await.unwrap();
+        for version in 1..=3 {
+            let path = migrations_dir().join(format!("v{}.sql", version));
+            let migration = std::fs::read_to_string(path).unwrap();
+            conn.batch_execute(&migration).await.unwrap();
+        }
+        conn.batch_execute(
+            "INSERT INTO bans (ban_id, ban_date, reason) VALUES (7, NULL, 'Spam');",
+        )
+        .await
+        .unwrap();
+
+        let version = db.migrate(&migrations_dir(), 3).await.unwrap();
+        let infos = conn
+            .query("SELECT db_version FROM general_info", &[])
+            .await
+            .unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].get::<_, i32>(0), version);
+
+        // Nothing is left to run, ban IDs continue after the existing ones
+        let again = db.migrate(&migrations_dir(), version).await.unwrap();
+        assert_eq!(again, version);
+        let ipid = db.ipid("127.0.0.1".parse().unwrap()).await.unwrap() as u32;
+        let hdids = ["hdid".to_string()];
+        let ban = db.ban(ipid, &hdids, "Spam", None, None).await.unwrap();
+        assert_eq!(ban.id, 8);
+        assert_eq!(db.hdid_ban("hdid").await.unwrap(), Some(ban));
+        assert_eq!(db.lift_ban(8).await.unwrap(), Some(vec![ipid]));
+        assert_eq!(db.lift_ban(8).await.unwrap(), None);
+
+        let event_time_types = conn
+            .query(
+                "SELECT DISTINCT data_type FROM information_schema.columns \
+                 WHERE column_name = 'event_time'",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(event_time_types.len(), 1);
+        assert_eq!(
+            event_time_types[0].get::<_, &str>(0),
+            "timestamp without time zone"
+        );
+
+        let bans = db.active_bans().await.unwrap();
+        assert_eq!(bans[0].0.id, 7);
+        assert_eq!(bans[0].0.ban_date, None);
+    }
+
+    #[test]
+    fn ban_message() {
+        let date = NaiveDate::from_ymd_opt(2020, 7, 1)
+            .unwrap()
+            .and_hms_opt(18, 30, 0)
+            .unwrap();
+        let mut ban = Ban {
+            id: 42,
+            reason: "Spam".into(),
+            banned_by: None,
+            ban_date: Some(date),
+            unban_date: Some(date),
+        };
+        assert_eq!(ban.message(), "Spam\nID: 42\nUntil: 2020-07-01 18:30 UTC");
+
+        ban.unban_date = None;
+        assert_eq!(ban.message(), "Spam\nID: 42\nUntil: forever");
+    }
 }
@@ -1,4 +1,5 @@
-pub use command_derive::{Command, WithStrIter};
+pub use command_derive::{ao_bool, Command, FromStrIter, WithStrIter};
 
 pub mod codec;
 pub mod database;
+pub mod websocket;
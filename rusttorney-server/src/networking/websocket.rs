@@ -0,0 +1,84 @@
+use crate::command::{ClientCommand, ServerCommand};
+use crate::networking::codec::AOMessageCodec;
+use bytes::BytesMut;
+use futures::{future, stream, Sink, SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Wraps WebSocket connection of a WebAO client into the same stream of
+/// commands and sink of commands, which `AOMessageCodec` provides for TCP.
+///
+/// Every text message carries AO packets, so it is decoded and encoded with
+/// `AOMessageCodec`. Other messages are ignored: pings are answered by
+/// tungstenite itself.
+pub fn framed(
+    ws: WebSocketStream<TcpStream>,
+) -> impl Stream<Item = Result<ClientCommand, anyhow::Error>>
+       + Sink<ServerCommand, Error = anyhow::Error> {
+    ws.with(|command| future::ready(encode(command)))
+        .flat_map(|message| stream::iter(decode(message)))
+}
+
+fn encode(command: ServerCommand) -> Result<Message, anyhow::Error> {
+    let mut buf = BytesMut::new();
+    AOMessageCodec.encode(command, &mut buf)?;
+    Ok(Message::Text(String::from_utf8(buf.to_vec())?))
+}
+
+fn decode(
+    message: Result<Message, tokio_tungstenite::tungstenite::Error>,
+) -> Vec<Result<ClientCommand, anyhow::Error>> {
+    let text = match message {
+        Ok(Message::Text(text)) => text,
+        Ok(_) => return Vec::new(),
+        Err(e) => return vec![Err(e.into())],
+    };
+
+    let mut buf = BytesMut::from(text.as_bytes());
+    let mut commands = Vec::new();
+    loop {
+        match AOMessageCodec.decode(&mut buf) {
+            Ok(Some(command)) => commands.push(Ok(command)),
+            Ok(None) => break,
+            Err(e) => {
+                commands.push(Err(e));
+                break;
+            }
+        }
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_text_message() {
+        let message = Message::Text("HI#hdid#%CH#1#%".into());
+        let commands: Vec<_> =
+            decode(Ok(message)).into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(
+            commands,
+            vec![
+                ClientCommand::Handshake("hdid".into()),
+                ClientCommand::KeepAlive(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_non_text_messages() {
+        assert!(decode(Ok(Message::Ping(vec![1, 2]))).is_empty());
+        assert!(decode(Ok(Message::Binary(b"HI#hdid#%".to_vec()))).is_empty());
+    }
+
+    #[test]
+    fn encode_text_message() {
+        let message = encode(ServerCommand::Decryptor(34)).unwrap();
+        assert_eq!(message, Message::Text("decryptor#34#%".into()));
+    }
+}
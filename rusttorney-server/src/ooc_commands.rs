@@ -0,0 +1,704 @@
+use crate::client_manager::{Client, Privilege};
+use crate::command::{CasePreferences, ServerCommand};
+use crate::networking::database::MiscEvent;
+use crate::networking::Command;
+use crate::server::AO2MessageHandler;
+use chrono::{Duration, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// Commands which clients can run by sending `/<name> <args...>` in OOC.
+///
+/// Arguments are separated by whitespace, unless the command takes the
+/// whole line, and parsed the same way as the arguments of the protocol
+/// commands. Everything else about a command is
+/// described by its entry in [`COMMANDS`].
+#[rustfmt::skip]
+#[derive(Debug, Command, PartialEq)]
+#[command(handler = "crate::server::AO2MessageHandler")]
+pub enum OOCCommand {
+    #[command(code = "help", handle = "ooc_help")]
+    Help,
+    #[command(code = "login", handle = "ooc_login")]
+    Login(String),
+    #[command(code = "logout", handle = "ooc_logout")]
+    Logout,
+    #[command(code = "kick", handle = "ooc_kick")]
+    Kick(Target, Vec<String>),
+    #[command(code = "ban", handle = "ooc_ban")]
+    Ban(u32, BanDuration, String, Vec<String>),
+    #[command(code = "unban", handle = "ooc_unban")]
+    Unban(i32),
+    #[command(code = "bans", handle = "ooc_bans")]
+    Bans,
+    #[command(code = "multiclients", handle = "ooc_multiclients")]
+    Multiclients(u32),
+    #[command(code = "cm", handle = "ooc_cm")]
+    CaseManager,
+    #[command(code = "uncm", handle = "ooc_uncm")]
+    UnCaseManager,
+    #[command(code = "casing", handle = "ooc_casing")]
+    Casing(Vec<String>),
+}
+
+/// Client to act upon: `<id>` for a client ID, `ipid:<ipid>` for every
+/// client with the IPID
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Client(u8),
+    Ipid(u32),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let res = match s.strip_prefix("ipid:") {
+            Some(ipid) => ipid.parse().map(Target::Ipid),
+            None => s.parse().map(Target::Client),
+        };
+        res.map_err(|_| anyhow::anyhow!("Invalid target: {:?}", s))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Client(id) => write!(f, "{}", id),
+            Target::Ipid(ipid) => write!(f, "ipid:{}", ipid),
+        }
+    }
+}
+
+/// How long a ban lasts: `perma`, or a number followed by `m`, `h`, `d` or
+/// `w`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BanDuration(pub Option<Duration>);
+
+impl FromStr for BanDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid duration: {:?}", s);
+        if s == "perma" {
+            return Ok(Self(None));
+        }
+
+        let unit_idx = s.len().checked_sub(1).ok_or_else(invalid)?;
+        if !s.is_char_boundary(unit_idx) {
+            return Err(invalid());
+        }
+        let amount: i64 = s[..unit_idx].parse().map_err(|_| invalid())?;
+        let duration = match &s[unit_idx..] {
+            "m" => Duration::try_minutes(amount),
+            "h" => Duration::try_hours(amount),
+            "d" => Duration::try_days(amount),
+            "w" => Duration::try_weeks(amount),
+            _ => None,
+        };
+        match duration {
+            Some(duration) if amount > 0 => Ok(Self(Some(duration))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for BanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [(i64, &str); 4] =
+            [(7 * 24 * 60, "w"), (24 * 60, "d"), (60, "h"), (1, "m")];
+
+        let minutes = match self.0 {
+            Some(duration) => duration.num_minutes(),
+            None => return f.write_str("perma"),
+        };
+        let (len, unit) = UNITS
+            .iter()
+            .find(|(len, _)| minutes % len == 0)
+            .unwrap_or(&UNITS[3]);
+        write!(f, "{}{}", minutes / len, unit)
+    }
+}
+
+/// Description of an [`OOCCommand`]
+#[derive(Debug)]
+pub struct CommandInfo {
+    /// Code of the command in [`OOCCommand`]
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// Arguments, as shown by `/help`
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// Least privilege needed to run the command
+    pub privilege: Privilege,
+    /// Takes the rest of the line as its only argument, spaces included
+    pub whole_line: bool,
+}
+
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "help",
+        aliases: &["h"],
+        usage: "",
+        help: "Lists the commands you can use.",
+        privilege: Privilege::Player,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "login",
+        aliases: &[],
+        usage: "<password>",
+        help: "Logs you in as a moderator.",
+        privilege: Privilege::Player,
+        whole_line: true,
+    },
+    CommandInfo {
+        name: "logout",
+        aliases: &[],
+        usage: "",
+        help: "Logs you out of the moderator account.",
+        privilege: Privilege::Moderator,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "kick",
+        aliases: &[],
+        usage: "<id|ipid:<ipid>> [reason]",
+        help: "Disconnects everyone with the IPID or HDID of the target.",
+        privilege: Privilege::Moderator,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "ban",
+        aliases: &[],
+        usage: "<ipid> <perma|duration, e.g. 30m, 12h, 7d, 2w> <reason>",
+        help: "Bans the IPID and its HDIDs and disconnects them.",
+        privilege: Privilege::Moderator,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "unban",
+        aliases: &[],
+        usage: "<ban_id>",
+        help: "Lifts the ban.",
+        privilege: Privilege::Moderator,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "bans",
+        aliases: &[],
+        usage: "",
+        help: "Lists the bans in effect.",
+        privilege: Privilege::Moderator,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "multiclients",
+        aliases: &["mc"],
+        usage: "<ipid>",
+        help: "Lists the clients connected from the IPID.",
+        privilege: Privilege::Moderator,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "cm",
+        aliases: &[],
+        usage: "",
+        help: "Makes you the case manager of the area, if it has none.",
+        privilege: Privilege::Player,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "uncm",
+        aliases: &[],
+        usage: "",
+        help: "Gives up your case manager role in the area.",
+        privilege: Privilege::Player,
+        whole_line: false,
+    },
+    CommandInfo {
+        name: "casing",
+        aliases: &[],
+        usage: "[cm] [def] [pro] [judge] [jury] [steno]",
+        help: "Notifies you about cases needing the roles, or stops notifying \
+               if none are given.",
+        privilege: Privilege::Player,
+        whole_line: false,
+    },
+];
+
+impl CommandInfo {
+    /// Finds command by its name or one of its aliases
+    pub fn find(name: &str) -> Option<&'static Self> {
+        COMMANDS
+            .iter()
+            .find(|info| info.name == name || info.aliases.contains(&name))
+    }
+
+    /// Splits what follows the command name into its arguments
+    fn args<'a>(&self, rest: &'a str) -> impl Iterator<Item = &'a str> {
+        let rest = rest.trim();
+        let whole_line = Some(rest).filter(|rest| !rest.is_empty());
+        let (whole_line, words) = match self.whole_line {
+            true => (whole_line, None),
+            false => (None, Some(rest.split_whitespace())),
+        };
+        whole_line.into_iter().chain(words.into_iter().flatten())
+    }
+
+    fn usage_line(&self) -> String {
+        let mut line = format!("/{}", self.name);
+        if !self.usage.is_empty() {
+            line.push(' ');
+            line.push_str(self.usage);
+        }
+        line
+    }
+}
+
+impl AO2MessageHandler {
+    /// Runs OOC command, `line` being the message without the leading `/`
+    pub(crate) async fn handle_ooc_command(
+        &mut self,
+        line: &str,
+    ) -> Result<(), anyhow::Error> {
+        let line = line.trim();
+        let (name, rest) =
+            line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+        let info = match CommandInfo::find(name) {
+            Some(info) => info,
+            None => {
+                return self.send_server_message(format!(
+                    "Unknown command: /{}. Use /help to list the commands.",
+                    name
+                ))
+            }
+        };
+        if self.client.privilege() < info.privilege {
+            return self.send_server_message(format!(
+                "You are not allowed to use /{}.",
+                info.name
+            ));
+        }
+
+        match OOCCommand::from_protocol(info.name, info.args(rest)) {
+            Ok(command) => command.handle(self).await,
+            Err(e) => self.send_server_message(format!(
+                "{}. Usage: {}",
+                e,
+                info.usage_line()
+            )),
+        }
+    }
+
+    pub async fn ooc_help(&mut self) -> Result<(), anyhow::Error> {
+        let privilege = self.client.privilege();
+        let lines: Vec<_> = COMMANDS
+            .iter()
+            .filter(|info| privilege >= info.privilege)
+            .map(|info| format!("{} - {}", info.usage_line(), info.help))
+            .collect();
+        self.send_server_message(format!(
+            "Available commands:\n{}",
+            lines.join("\n")
+        ))
+    }
+
+    pub async fn ooc_login(
+        &mut self,
+        password: String,
+    ) -> Result<(), anyhow::Error> {
+        if self.client.is_mod {
+            return self.send_server_message("You are already logged in.");
+        }
+        let checked =
+            self.client_manager.lock().await.check_login(self.client.ipid);
+        if let Err(left) = checked {
+            return self.send_server_message(format!(
+                "Too many login attempts. You can try again in {} seconds.",
+                left.as_secs()
+            ));
+        }
+
+        // An empty modpass disables logging in
+        let modpass = &self.config.general.modpass;
+        if modpass.is_empty() || &password != modpass {
+            self.db.log_login(self.client.ipid, None).await?;
+            return self.send_server_message("Invalid password.");
+        }
+
+        self.client.is_mod = true;
+        self.client_manager.lock().await.update_client(self.client.clone());
+        self.db.log_login(self.client.ipid, Some("moderator")).await?;
+        log::info!("Client {} logged in as a moderator", self.client.id);
+        self.send_server_message("Logged in as a moderator.")
+    }
+
+    pub async fn ooc_logout(&mut self) -> Result<(), anyhow::Error> {
+        self.client.is_mod = false;
+        self.client_manager.lock().await.update_client(self.client.clone());
+        self.send_server_message("Logged out.")
+    }
+
+    pub async fn ooc_kick(
+        &mut self,
+        target: Target,
+        reason: Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        let reason = reason.join(" ");
+        let client_manager = self.client_manager.lock().await;
+        let targets: Vec<_> = match target {
+            Target::Client(id) => {
+                client_manager.clients.get(&id).into_iter().collect()
+            }
+            Target::Ipid(ipid) => client_manager
+                .clients
+                .values()
+                .filter(|c| c.ipid == ipid)
+                .collect(),
+        };
+        let ipid = match targets.first() {
+            Some(client) => client.ipid,
+            None => return self.send_server_message("No such client."),
+        };
+        let hdids: Vec<_> = targets.iter().map(|c| c.hdid.clone()).collect();
+
+        let kicked = client_manager.disconnect_filtered(
+            |c| c.ipid == ipid || is_hdid_in(c, &hdids),
+            ServerCommand::Kicked(reason.clone()),
+        );
+        drop(client_manager);
+
+        self.db
+            .log_misc_event(
+                MiscEvent::Kick,
+                Some(self.client.ipid),
+                Some(ipid),
+                &reason,
+            )
+            .await?;
+        log::info!(
+            "Client {} kicked IPID {}: {:?}",
+            self.client.id,
+            ipid,
+            reason
+        );
+        self.send_server_message(format!(
+            "Kicked {} client(s) of IPID {}.",
+            kicked.len(),
+            ipid
+        ))
+    }
+
+    pub async fn ooc_ban(
+        &mut self,
+        ipid: u32,
+        duration: BanDuration,
+        reason_start: String,
+        reason_rest: Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        let reason = std::iter::once(reason_start)
+            .chain(reason_rest)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let unban_date = duration.0.map(|d| Utc::now().naive_utc() + d);
+
+        let hdids = self.db.hdids(ipid).await?;
+        let ban = self
+            .db
+            .ban(ipid, &hdids, &reason, unban_date, Some(self.client.ipid))
+            .await?;
+
+        let banned = self.client_manager.lock().await.disconnect_filtered(
+            |c| c.ipid == ipid || is_hdid_in(c, &hdids),
+            ServerCommand::Banned(ban.message()),
+        );
+
+        self.db
+            .log_misc_event(
+                MiscEvent::Ban,
+                Some(self.client.ipid),
+                Some(ipid),
+                &reason,
+            )
+            .await?;
+        log::info!(
+            "Client {} banned IPID {}: {:?}",
+            self.client.id,
+            ipid,
+            reason
+        );
+        self.send_server_message(format!(
+            "Banned IPID {} with ban ID {}, disconnected {} client(s).",
+            ipid,
+            ban.id,
+            banned.len()
+        ))
+    }
+
+    pub async fn ooc_unban(
+        &mut self,
+        ban_id: i32,
+    ) -> Result<(), anyhow::Error> {
+        let ipids = match self.db.lift_ban(ban_id).await? {
+            Some(ipids) => ipids,
+            None => {
+                return self.send_server_message(format!(
+                    "No active ban with ID {}.",
+                    ban_id
+                ))
+            }
+        };
+
+        // Bans covering only HDIDs have no IPID to record
+        let targets = match ipids.as_slice() {
+            [] => vec![None],
+            ipids => ipids.iter().copied().map(Some).collect(),
+        };
+        for target_ipid in targets {
+            self.db
+                .log_misc_event(
+                    MiscEvent::Unban,
+                    Some(self.client.ipid),
+                    target_ipid,
+                    &ban_id.to_string(),
+                )
+                .await?;
+        }
+        log::info!("Client {} lifted ban {}", self.client.id, ban_id);
+        self.send_server_message(format!("Lifted ban {}.", ban_id))
+    }
+
+    pub async fn ooc_bans(&mut self) -> Result<(), anyhow::Error> {
+        let bans = self.db.active_bans().await?;
+        if bans.is_empty() {
+            return self.send_server_message("There are no bans in effect.");
+        }
+
+        let lines: Vec<_> = bans
+            .iter()
+            .map(|(ban, ipids)| {
+                let ipids: Vec<_> =
+                    ipids.iter().map(ToString::to_string).collect();
+                format!(
+                    "{}: IPID {} until {}, {}",
+                    ban.id,
+                    ipids.join(", "),
+                    ban.until(),
+                    ban.reason
+                )
+            })
+            .collect();
+        self.send_server_message(format!(
+            "Bans in effect:\n{}",
+            lines.join("\n")
+        ))
+    }
+
+    pub async fn ooc_multiclients(
+        &mut self,
+        ipid: u32,
+    ) -> Result<(), anyhow::Error> {
+        let client_manager = self.client_manager.lock().await;
+        let mut clients: Vec<_> =
+            client_manager.clients_of_ipid(ipid).collect();
+        if clients.is_empty() {
+            return self.send_server_message(format!(
+                "There are no clients with IPID {}.",
+                ipid
+            ));
+        }
+        clients.sort_by_key(|c| c.id);
+
+        let lines: Vec<_> = clients
+            .iter()
+            .map(|c| {
+                let character = client_manager
+                    .character_name(c.char_id)
+                    .unwrap_or("Spectator");
+                let area = client_manager
+                    .areas
+                    .get(c.area)
+                    .map_or("?", |area| area.name.as_str());
+                format!("[{}] {} in {}, HDID {}", c.id, character, area, c.hdid)
+            })
+            .collect();
+        self.send_server_message(format!(
+            "IPID {} has {} client(s):\n{}",
+            ipid,
+            clients.len(),
+            lines.join("\n")
+        ))
+    }
+
+    pub async fn ooc_cm(&mut self) -> Result<(), anyhow::Error> {
+        let mut client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get_mut(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        if area.case_managers.contains(&self.client.id) {
+            return self.send_server_message(
+                "You are already a case manager of this area.",
+            );
+        }
+        // Moderators may join the case managers already running the case
+        if !area.case_managers.is_empty()
+            && self.client.privilege() < Privilege::Moderator
+        {
+            return self
+                .send_server_message("This area already has a case manager.");
+        }
+        area.case_managers.insert(self.client.id);
+        let message = format!(
+            "Client {} is now a case manager of {}.",
+            self.client.id, area.name
+        );
+        client_manager.broadcast_area(
+            self.client.area,
+            ServerCommand::OOCMessage(
+                self.config.general.hostname.clone(),
+                message,
+                true,
+            ),
+        );
+        Ok(())
+    }
+
+    pub async fn ooc_uncm(&mut self) -> Result<(), anyhow::Error> {
+        let mut client_manager = self.client_manager.lock().await;
+        let area = client_manager
+            .areas
+            .get_mut(self.client.area)
+            .ok_or_else(|| anyhow::anyhow!("Client is in unknown area"))?;
+
+        if !area.case_managers.remove(&self.client.id) {
+            return self.send_server_message(
+                "You are not a case manager of this area.",
+            );
+        }
+        let message = format!(
+            "Client {} is no longer a case manager of {}.",
+            self.client.id, area.name
+        );
+        client_manager.broadcast_area(
+            self.client.area,
+            ServerCommand::OOCMessage(
+                self.config.general.hostname.clone(),
+                message,
+                true,
+            ),
+        );
+        Ok(())
+    }
+
+    pub async fn ooc_casing(
+        &mut self,
+        roles: Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        let preferences = match CasePreferences::from_roles(&roles) {
+            Ok(preferences) => preferences,
+            Err(e) => return self.send_server_message(e.to_string()),
+        };
+        self.client.case_preferences = preferences;
+        self.client_manager.lock().await.update_client(self.client.clone());
+
+        match preferences.roles().as_slice() {
+            [] => self
+                .send_server_message("You will not be notified about cases."),
+            roles => self.send_server_message(format!(
+                "You will be notified about cases needing: {}.",
+                roles.join(", ")
+            )),
+        }
+    }
+}
+
+/// Whether the client uses one of the HDIDs. Clients which haven't sent
+/// their HDID yet have an empty one, which matches nothing.
+fn is_hdid_in(client: &Client, hdids: &[String]) -> bool {
+    !client.hdid.is_empty() && hdids.contains(&client.hdid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_command_is_described() {
+        for info in COMMANDS {
+            let res = OOCCommand::from_protocol(
+                info.name,
+                std::iter::empty::<&str>(),
+            );
+            if let Err(e) = res {
+                assert!(!e.to_string().starts_with("Unknown command code"));
+            }
+        }
+        assert_eq!(CommandInfo::find("h").unwrap().name, "help");
+        assert!(CommandInfo::find("logn").is_none());
+    }
+
+    #[test]
+    fn parse_command_args() {
+        let parse = |name, line: &str| {
+            OOCCommand::from_protocol(name, line.split_whitespace())
+        };
+
+        assert_eq!(
+            parse("login", " hunter2 ").unwrap(),
+            OOCCommand::Login("hunter2".into())
+        );
+        assert!(parse("login", "").is_err());
+        let login = CommandInfo::find("login").unwrap();
+        assert_eq!(
+            OOCCommand::from_protocol("login", login.args(" correct horse "))
+                .unwrap(),
+            OOCCommand::Login("correct horse".into())
+        );
+        assert!(OOCCommand::from_protocol("login", login.args(" ")).is_err());
+        assert!(parse("logout", "now").is_err());
+        assert_eq!(
+            parse("kick", "ipid:42 spamming  ads").unwrap(),
+            OOCCommand::Kick(
+                Target::Ipid(42),
+                vec!["spamming".into(), "ads".into()]
+            )
+        );
+        assert_eq!(
+            parse("kick", "3").unwrap(),
+            OOCCommand::Kick(Target::Client(3), vec![])
+        );
+        assert!(parse("kick", "ipid:").is_err());
+        assert_eq!(
+            parse("ban", "42 12h spam").unwrap(),
+            OOCCommand::Ban(
+                42,
+                BanDuration(Some(Duration::hours(12))),
+                "spam".into(),
+                vec![]
+            )
+        );
+        assert!(parse("ban", "42 perma").is_err());
+    }
+
+    #[test]
+    fn parse_ban_durations() {
+        let parse = |s: &str| s.parse::<BanDuration>().map(|d| d.0);
+
+        assert_eq!(parse("perma").unwrap(), None);
+        assert_eq!(parse("30m").unwrap(), Some(Duration::minutes(30)));
+        assert_eq!(parse("7d").unwrap(), Some(Duration::days(7)));
+        assert_eq!(parse("2w").unwrap(), Some(Duration::weeks(2)));
+        assert!(parse("0h").is_err());
+        assert!(parse("-1d").is_err());
+        assert!(parse("12").is_err());
+        assert!(parse("h").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("1ч").is_err());
+        assert_eq!(BanDuration(Some(Duration::days(14))).to_string(), "2w");
+        assert_eq!(BanDuration(Some(Duration::minutes(90))).to_string(), "90m");
+    }
+}
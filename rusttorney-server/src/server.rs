@@ -1,56 +1,82 @@
+use crate::advertiser;
 use crate::command::{
     CasePreferences, ClientCommand, EvidenceArgs, ServerCommand,
 };
-use crate::config::Config;
+use crate::config::{AdvertiserMode, Config, OutOfOrderPolicy};
+use crate::connection_state::ConnectionState;
 
-use crate::client_manager::{Client, ClientManager};
+use crate::client_manager::{Client, ClientManager, ClientSender};
+use crate::master_server_client;
 use crate::networking::codec::AOMessageCodec;
 use crate::networking::database::DbWrapper;
-use futures::stream::SplitSink;
-use futures::{SinkExt, StreamExt};
+use crate::networking::websocket;
+use crate::networking::Command;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::pin::Pin;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 
 use crate::prompt;
+use crate::text_filter::Violation;
 use futures::channel::mpsc;
 use futures::channel::oneshot::{channel, Receiver, Sender};
 use std::convert::Infallible;
 use std::io::{stdin, Read};
 use std::net::IpAddr;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::select;
 use tokio::sync::Mutex;
 use tokio::time::Duration;
 use tokio_util::codec::{Decoder, Framed};
 
+/// Name of the server software, as shown to clients and the master server
+pub(crate) const SOFTWARE: &str = "rusttorney";
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub struct AOServer {
     config: Arc<Config>,
     db: DbWrapper,
     client_manager: Arc<Mutex<ClientManager>>,
 }
 
+/// Incoming commands of a client, regardless of the transport it uses
+pub(crate) type CommandStream = Pin<
+    Box<dyn Stream<Item = Result<ClientCommand, anyhow::Error>> + Send + Sync>,
+>;
+
 pub struct AO2MessageHandler {
-    pub(crate) socket: Framed<TcpStream, AOMessageCodec>,
+    pub(crate) stream: CommandStream,
+    pub(crate) sender: ClientSender,
+    /// Fires when the writer task stops, i.e. the socket can't be written to
+    /// anymore or the client was disconnected by the server
+    writer_closed: Receiver<()>,
     pub(crate) db: DbWrapper,
     pub(crate) client_manager: Arc<Mutex<ClientManager>>,
     pub(crate) ch_tx: mpsc::Sender<()>,
     pub(crate) client: Client,
-    pub(crate) software: String,
-    pub(crate) version: String,
+    pub(crate) state: ConnectionState,
     pub(crate) config: Arc<Config>,
 }
 
 impl AO2MessageHandler {
-    pub async fn new(
-        mut socket: Framed<TcpStream, AOMessageCodec>,
+    /// Creates handler of a client connected through `socket`, which may be
+    /// either a framed TCP stream or a WebSocket connection
+    pub async fn new<S>(
+        socket: S,
         db: DbWrapper,
         client_manager: Arc<Mutex<ClientManager>>,
         timeout: u64,
         timeout_tx: Sender<()>,
         ip: IpAddr,
         config: Arc<Config>,
-    ) -> Result<Self, anyhow::Error> {
+    ) -> Result<Self, anyhow::Error>
+    where
+        S: Stream<Item = Result<ClientCommand, anyhow::Error>>
+            + Sink<ServerCommand, Error = anyhow::Error>
+            + Send
+            + 'static,
+    {
         let (ch_tx, mut ch_rx) = futures::channel::mpsc::channel(1);
 
         tokio::spawn(async move {
@@ -77,8 +103,29 @@ impl AO2MessageHandler {
             }
         });
 
+        let (sink, stream) = socket.split();
+        let (sender, receiver) = mpsc::unbounded();
+        let (writer_closed_tx, writer_closed) = channel();
+
+        // Writer task: the only place which writes into the socket. Handlers
+        // of this and other clients send commands through `sender`.
+        tokio::spawn(async move {
+            if let Err(e) = receiver.map(Ok).forward(sink).await {
+                log::debug!("Failed to write to the socket: {}", e);
+            }
+            let _ = writer_closed_tx.send(());
+        });
+
+        // TODO: GeoIP
+        // Look the IPID up before locking, so that other clients don't wait
+        // for the database
+        let ipid = db.ipid(ip).await? as u32;
+        if let Some(ban) = db.ipid_ban(ipid).await? {
+            sender.unbounded_send(ServerCommand::BanReason(ban.message()))?;
+            anyhow::bail!("IPID {} is banned (ban {})", ipid, ban.id);
+        }
         let client =
-            client_manager.lock().await.new_client(&mut socket, ip).await?;
+            client_manager.lock().await.new_client(sender.clone(), ipid)?;
         log::info!(
             "Client with IPID: {} connected! His ip is: {}",
             &client.ipid,
@@ -86,25 +133,54 @@ impl AO2MessageHandler {
         );
 
         Ok(Self {
-            socket,
+            stream: Box::pin(stream),
+            sender,
+            writer_closed,
             db,
             client_manager,
             ch_tx,
             client,
-            software: "rusttorney".into(),
-            version: "0.0.1".into(),
+            state: ConnectionState::Connected,
             config,
         })
     }
 
     pub(crate) async fn player_count(&self) -> u8 {
-        self.client_manager
-            .lock()
-            .await
-            .clients
-            .iter()
-            .filter(|c| c.char_id != 1)
-            .count() as u8
+        self.client_manager.lock().await.player_count()
+    }
+
+    /// Sends command to this client
+    pub(crate) fn send(
+        &self,
+        command: ServerCommand,
+    ) -> Result<(), anyhow::Error> {
+        self.sender
+            .unbounded_send(command.adapt_to(&self.client.features))
+            .map_err(Into::into)
+    }
+
+    /// Tells this client what was wrong with its `what` (e.g. "message")
+    pub(crate) fn report_violations(
+        &self,
+        what: &str,
+        violations: &[Violation],
+    ) -> Result<(), anyhow::Error> {
+        for violation in violations {
+            self.send_server_message(format!("Your {} {}.", what, violation))?;
+        }
+        Ok(())
+    }
+
+    /// Sends OOC message from the server to this client
+    pub(crate) fn send_server_message(
+        &self,
+        message: impl Into<String>,
+    ) -> Result<(), anyhow::Error> {
+        self.send(ServerCommand::OOCMessage(
+            self.config.general.hostname.clone(),
+            message.into(),
+            true,
+        ))
     }
 
     async fn start_handling(
@@ -118,9 +194,12 @@ impl AO2MessageHandler {
                 _ = &mut timeout_rx => {
                     return Err(anyhow::anyhow!("Client disconnected because of timeout!"));
                 }
-                res = self.socket.next() => {
+                _ = &mut self.writer_closed => {
+                    return Err(anyhow::anyhow!("Client connection was closed!"));
+                }
+                res = self.stream.next() => {
                     if let Some(parsed) = res {
-                        parsed?.handle(self).await?;
+                        self.dispatch(parsed?).await?;
                     } else {
                         return Err(anyhow::anyhow!("Client disconnected!"));
                     }
@@ -128,35 +207,66 @@ impl AO2MessageHandler {
             }
         }
     }
+
+    /// Handles the command if the client may send it in its current state
+    async fn dispatch(
+        &mut self,
+        command: ClientCommand,
+    ) -> Result<(), anyhow::Error> {
+        if !self.state.allows(&command) {
+            log::warn!(
+                "Client {} sent {} out of order, in state {:?}",
+                self.client.id,
+                command.ident(),
+                self.state
+            );
+            return match self.config.out_of_order_packets {
+                OutOfOrderPolicy::Ignore => Ok(()),
+                OutOfOrderPolicy::Disconnect => Err(anyhow::anyhow!(
+                    "Client sent {} out of order",
+                    command.ident()
+                )),
+            };
+        }
+
+        let next = self.state.after(&command);
+        command.handle(self).await?;
+        self.state = next;
+        Ok(())
+    }
+}
+
+impl Drop for AO2MessageHandler {
+    /// Frees the client's ID, character and area slot, even if a handler
+    /// failed or panicked
+    fn drop(&mut self) {
+        let client_manager = self.client_manager.clone();
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            client_manager.lock().await.remove_client(&client)
+        });
+    }
 }
 
 impl AOServer {
     pub fn new(config: Arc<Config>, db: DbWrapper) -> anyhow::Result<Self> {
-        let playerlimit = config.general.playerlimit;
         Ok(Self {
-            config,
+            config: config.clone(),
             db: db.clone(),
-            client_manager: Arc::new(Mutex::new(ClientManager::new(
-                playerlimit,
-                db,
-            ))),
+            client_manager: Arc::new(Mutex::new(ClientManager::new(config))),
         })
     }
 
     async fn begin_migration(&mut self) -> anyhow::Result<()> {
-        const LATEST_VERSION: i32 = 3;
+        const LATEST_VERSION: i32 = 7;
 
         log::debug!("Getting pool connection for migration...");
         let mut conn = self.db.get().await?;
         let stmt = conn.prepare("SELECT db_version FROM general_info").await;
 
+        // Without `general_info` the database is empty
         let mut current_version = match stmt {
-            Err(_) => {
-                if !prompt("Begin the migration?") {
-                    return Ok(());
-                }
-                self.migrate().await?
-            }
+            Err(_) => 0,
             Ok(stmt) => {
                 let row = conn.query_one(&stmt, &[]).await?;
                 row.get(0_usize)
@@ -167,35 +277,20 @@ impl AOServer {
             if !prompt("Begin the migration?") {
                 return Ok(());
             }
-            current_version = self.migrate().await?;
+            current_version = self.migrate(current_version).await?;
         };
 
         log::info!("Current DB version is: v{}", current_version);
         Ok(())
     }
 
-    async fn migrate(&mut self) -> anyhow::Result<i32> {
-        let mut conn = self.db.get().await?;
-        log::info!("Migrating database...");
-        let mut migrations = std::fs::read_dir("migrations")?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-
-        migrations.sort();
-
-        for migration in migrations {
-            log::debug!("Executing migration: {:?}", &migration);
-            let migration_stmt = std::fs::read_to_string(migration)?;
-            let tx = conn.transaction().await?;
-            tx.batch_execute(&migration_stmt).await?;
-            tx.commit().await?;
-        }
+    async fn migrate(&mut self, from: i32) -> anyhow::Result<i32> {
+        log::info!("Migrating database from v{}...", from);
+        let version = self.db.migrate(Path::new("migrations"), from).await?;
         log::info!("Succesfully migrated!");
         log::debug!("GCing the DB...");
-        conn.execute("VACUUM", &[]).await?;
-        let row =
-            conn.query_one("SELECT db_version FROM general_info", &[]).await?;
-        Ok(row.get(0_usize))
+        self.db.get().await?.execute("VACUUM", &[]).await?;
+        Ok(version)
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
@@ -212,38 +307,145 @@ impl AOServer {
 
         let mut listener = TcpListener::bind(addr).await?;
 
+        if self.config.masterserver.use_masterserver {
+            self.spawn_advertiser().await;
+        }
+
+        if self.config.general.use_websockets {
+            match self.config.general.websocket_port {
+                Some(port) => self.spawn_websocket_listener(port).await?,
+                None => log::warn!(
+                    "use_websockets is set, but websocket_port is missing"
+                ),
+            }
+        }
+
         loop {
-            let db = self.db.clone();
-            let config = self.config.clone();
-            let client_manager = self.client_manager.clone();
             let (socket, c) = listener.accept().await?;
             log::debug!("got incoming connection from: {:?}", &c);
-            let timeout = self.config.timeout as u64;
-
-            tokio::spawn(async move {
-                let mut framed = AOMessageCodec.framed(socket);
-                let (timeout_tx, timeout_rx) = channel();
-
-                // https://github.com/AttorneyOnline/tsuserver3/blob/master/server/network/aoprotocol.py#L135
-                framed.send(ServerCommand::Decryptor(34)).await.unwrap();
-
-                let mut handler = AO2MessageHandler::new(
-                    framed,
-                    db,
-                    client_manager,
-                    timeout,
-                    timeout_tx,
-                    c.ip(),
-                    config,
-                )
-                .await
-                .unwrap();
 
-                handler
-                    .start_handling(timeout_rx)
-                    .await
-                    .map_err(|e| log::error!("{}", e));
-            });
+            tokio::spawn(self.serve(AOMessageCodec.framed(socket), c.ip()));
+        }
+    }
+
+    /// Lists the server on the master server or the HTTP server list
+    async fn spawn_advertiser(&self) {
+        match self.config.masterserver.mode {
+            AdvertiserMode::Legacy => {
+                tokio::spawn(master_server_client::advertise(
+                    self.config.clone(),
+                    SOFTWARE.into(),
+                ));
+            }
+            AdvertiserMode::Http => {
+                let players =
+                    self.client_manager.lock().await.watch_player_count();
+                tokio::spawn(advertiser::advertise(
+                    self.config.clone(),
+                    players,
+                ));
+            }
+        }
+    }
+
+    /// Accepts WebAO clients in the background. Once the handshake is done,
+    /// they are handled the same way as TCP ones.
+    async fn spawn_websocket_listener(&self, port: u32) -> anyhow::Result<()> {
+        let addr = format!("{}:{}", self.config.general.host, port);
+        log::info!("Binding WebSocket listener to address: {}", &addr);
+        let mut listener = TcpListener::bind(addr).await?;
+        let server = self.clone_handles();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, c) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!("Failed to accept WebSocket client: {}", e);
+                        continue;
+                    }
+                };
+                log::debug!("got incoming WebSocket connection from: {:?}", &c);
+                let serve = server.serve_websocket(socket, c.ip());
+                tokio::spawn(serve);
+            }
+        });
+        Ok(())
+    }
+
+    fn clone_handles(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            db: self.db.clone(),
+            client_manager: self.client_manager.clone(),
+        }
+    }
+
+    fn serve_websocket(
+        &self,
+        socket: TcpStream,
+        ip: IpAddr,
+    ) -> impl std::future::Future<Output = ()> {
+        let server = self.clone_handles();
+
+        async move {
+            match tokio_tungstenite::accept_async(socket).await {
+                Ok(ws) => server.serve(websocket::framed(ws), ip).await,
+                Err(e) => log::debug!("WebSocket handshake failed: {}", e),
+            }
+        }
+    }
+
+    /// Handles a single client until it disconnects
+    fn serve<S>(
+        &self,
+        mut socket: S,
+        ip: IpAddr,
+    ) -> impl std::future::Future<Output = ()>
+    where
+        S: Stream<Item = Result<ClientCommand, anyhow::Error>>
+            + Sink<ServerCommand, Error = anyhow::Error>
+            + Send
+            + Unpin
+            + 'static,
+    {
+        let db = self.db.clone();
+        let config = self.config.clone();
+        let client_manager = self.client_manager.clone();
+        let timeout = self.config.timeout as u64;
+
+        async move {
+            let (timeout_tx, timeout_rx) = channel();
+
+            // https://github.com/AttorneyOnline/tsuserver3/blob/master/server/network/aoprotocol.py#L135
+            if let Err(e) = socket.send(ServerCommand::Decryptor(34)).await {
+                log::debug!("Failed to greet the client: {}", e);
+                return;
+            }
+
+            let mut handler = match AO2MessageHandler::new(
+                socket,
+                db,
+                client_manager,
+                timeout,
+                timeout_tx,
+                ip,
+                config,
+            )
+            .await
+            {
+                Ok(handler) => handler,
+                Err(e) => {
+                    log::error!("{}", e);
+                    return;
+                }
+            };
+
+            // The client is removed when the handler is dropped
+            handler
+                .start_handling(timeout_rx)
+                .await
+                .map_err(|e| log::error!("{}", e));
         }
     }
 }
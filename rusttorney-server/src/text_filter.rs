@@ -0,0 +1,145 @@
+use crate::config::Config;
+use std::fmt;
+
+/// Problem found in a text sent by a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Text is longer than the limit, so it was rejected
+    TooLong(usize),
+    /// Text was longer than the limit, so it was cut
+    Truncated(usize),
+    /// Runs of combining characters were stripped from the text
+    Zalgo,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::TooLong(limit) => {
+                write!(f, "is too long, the limit is {} characters", limit)
+            }
+            Violation::Truncated(limit) => {
+                write!(f, "was cut to {} characters", limit)
+            }
+            Violation::Zalgo => f.write_str("had zalgo removed"),
+        }
+    }
+}
+
+/// Text after filtering, along with what was wrong with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filtered {
+    pub text: String,
+    pub violations: Vec<Violation>,
+}
+
+/// Applies `max_chars` and `zalgo_tolerance` to texts sent by clients
+pub struct TextFilter {
+    max_chars: usize,
+    zalgo_tolerance: usize,
+}
+
+impl TextFilter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_chars: config.max_chars as usize,
+            zalgo_tolerance: config.zalgo_tolerance as usize,
+        }
+    }
+
+    /// Strips zalgo and rejects the text if it is still too long
+    pub fn reject_long(&self, text: &str) -> Result<Filtered, Violation> {
+        let filtered = self.strip_zalgo(text);
+        if filtered.text.chars().count() > self.max_chars {
+            return Err(Violation::TooLong(self.max_chars));
+        }
+        Ok(filtered)
+    }
+
+    /// Strips zalgo and cuts the text if it is still too long
+    pub fn truncate(&self, text: &str) -> Filtered {
+        let mut filtered = self.strip_zalgo(text);
+        if let Some((idx, _)) = filtered.text.char_indices().nth(self.max_chars)
+        {
+            filtered.text.truncate(idx);
+            filtered.violations.push(Violation::Truncated(self.max_chars));
+        }
+        filtered
+    }
+
+    /// Removes every run of combining characters longer than
+    /// `zalgo_tolerance`
+    fn strip_zalgo(&self, text: &str) -> Filtered {
+        let mut res = String::with_capacity(text.len());
+        let mut run = String::new();
+        let mut stripped = false;
+
+        for c in text.chars().chain(std::iter::once('\0')) {
+            if is_combining(c) {
+                run.push(c);
+                continue;
+            }
+            if run.chars().count() > self.zalgo_tolerance {
+                stripped = true;
+            } else {
+                res.push_str(&run);
+            }
+            run.clear();
+            res.push(c);
+        }
+        // Forget the sentinel
+        res.pop();
+
+        let violations = if stripped { vec![Violation::Zalgo] } else { vec![] };
+        Filtered { text: res, violations }
+    }
+}
+
+/// Whether the character is a combining mark, which is what zalgo is made of
+fn is_combining(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{0483}'..='\u{0489}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE20}'..='\u{FE2F}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> TextFilter {
+        TextFilter { max_chars: 10, zalgo_tolerance: 2 }
+    }
+
+    #[test]
+    fn strip_zalgo() {
+        let text = "Z\u{0351}\u{0344}\u{0300}a\u{0301}\u{0302}lgo\u{0489}\u{0489}\u{0489}";
+        let filtered = filter().reject_long(text).unwrap();
+
+        assert_eq!(filtered.text, "Za\u{0301}\u{0302}lgo");
+        assert_eq!(filtered.violations, vec![Violation::Zalgo]);
+        assert!(filter().reject_long("Café").unwrap().violations.is_empty());
+    }
+
+    #[test]
+    fn limit_length() {
+        let text = "Objection!!";
+        assert_eq!(filter().reject_long(text), Err(Violation::TooLong(10)));
+        assert_eq!(
+            filter().truncate(text),
+            Filtered {
+                text: "Objection!".into(),
+                violations: vec![Violation::Truncated(10)]
+            }
+        );
+        // Zalgo doesn't count towards the limit
+        let text = "Hold it!!\u{0300}\u{0300}\u{0300}";
+        assert_eq!(filter().reject_long(text).unwrap().text, "Hold it!!");
+        assert_eq!(filter().truncate("Привет, мир!").text, "Привет, ми");
+    }
+}